@@ -0,0 +1,80 @@
+/**
+© 2020 PERLUR Group
+
+SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
+
+*/
+
+use cardano_ouroboros_network::{
+    mux,
+    protocols::{
+        handshake::{node_to_node_versions, HandshakeProtocol, PROTOCOL_VERSION_11},
+        peersharing::PeerSharingProtocol,
+    },
+};
+use std::{
+    collections::{HashSet, VecDeque},
+    env,
+    net::SocketAddr,
+};
+use log::{info, error};
+
+mod common;
+
+const MAX_HOSTS: usize = 20;
+
+async fn share_peers(host: &str, port: u16, magic: u32) -> Result<Vec<SocketAddr>, String> {
+    let channel = match mux::tcp::connect(host, port).await {
+        Ok(channel) => channel,
+        Err(_) => { return Err("Could not connect.".to_string()) }
+    };
+    /* Relays only run PeerSharing when we ask for it in the handshake. */
+    let mut versions = node_to_node_versions(magic);
+    for (_, data) in versions.range_mut(PROTOCOL_VERSION_11..) {
+        data.peer_sharing = 1;
+    }
+    channel.handshake_with(HandshakeProtocol::new(magic).with_versions(versions)).await?;
+    let peer_sharing = channel.run(PeerSharingProtocol::new(10)).await?;
+    Ok(peer_sharing.peers().to_vec())
+}
+
+#[tokio::main]
+async fn main() {
+    let cfg = common::init();
+    let magic = cfg.magic;
+
+    let mut args: Vec<String> = env::args().collect();
+
+    args.remove(0);
+
+    /* Start at the configured host by default. */
+    if args.is_empty() {
+        args = vec![cfg.host.clone()];
+    }
+
+    let mut queue: VecDeque<(String, u16)> = args.into_iter().map(|host| (host, cfg.port)).collect();
+    let mut seen: HashSet<(String, u16)> = queue.iter().cloned().collect();
+    let mut visited = 0;
+
+    while let Some((host, port)) = queue.pop_front() {
+        if visited == MAX_HOSTS {
+            break;
+        }
+        visited += 1;
+        match share_peers(&host, port, magic).await {
+            Ok(peers) => {
+                info!("Crawl {}:{} shared {} peers", &host, port, peers.len());
+                for peer in peers {
+                    info!("Crawl {}:{} peer {}", &host, port, peer);
+                    let peer = (peer.ip().to_string(), peer.port());
+                    if seen.insert(peer.clone()) {
+                        queue.push_back(peer);
+                    }
+                }
+            }
+            Err(error) => {
+                error!("Crawl {}:{} failed! : {:?}", &host, port, error);
+            }
+        }
+    }
+}
@@ -11,10 +11,7 @@ use std::{
     time::Duration,
 };
 use log::{info, error};
-use futures::{
-    executor::block_on,
-    future::join_all,
-};
+use futures::future::join_all;
 
 mod common;
 
@@ -30,30 +27,29 @@ async fn ping(host: &String, port: u16, magic: u32) -> Result<(Duration, Duratio
     Ok((connect_duration, total_duration))
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let cfg = common::init();
     let port = cfg.port;
     let magic = cfg.magic;
 
-    block_on(async {
-        let mut args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
 
-        args.remove(0);
+    args.remove(0);
 
-        /* Use configured host by default. */
-        if args.len() == 0 {
-            args = vec![cfg.host.clone()];
-        }
+    /* Use configured host by default. */
+    if args.len() == 0 {
+        args = vec![cfg.host.clone()];
+    }
 
-        join_all(args.iter().map(|host| async move {
-            match ping(&host.clone(), port, magic).await {
-                Ok((connect_duration, total_duration)) => {
-                    info!("Ping {}:{} success! : connect_duration: {}, total_duration: {}", &host, port, connect_duration.as_millis(), total_duration.as_millis());
-                }
-                Err(error) => {
-                    error!("Ping {}:{} failed! : {:?}", &host, port, error);
-                }
+    join_all(args.iter().map(|host| async move {
+        match ping(&host.clone(), port, magic).await {
+            Ok((connect_duration, total_duration)) => {
+                info!("Ping {}:{} success! : connect_duration: {}, total_duration: {}", &host, port, connect_duration.as_millis(), total_duration.as_millis());
             }
-        })).await;
-    });
+            Err(error) => {
+                error!("Ping {}:{} failed! : {:?}", &host, port, error);
+            }
+        }
+    })).await;
 }
@@ -92,7 +92,8 @@ impl Listener for PoolTool {
                         slot_no: header.slot_number,
                         block_hash: hex::encode(&header.hash),
                         parent_hash: hex::encode(&header.prev_hash),
-                        leader_vrf: hex::encode(&header.leader_vrf_0),
+                        /* Praos headers only have the VRF result the leader value is derived from. */
+                        leader_vrf: header.leader_vrf_0.as_ref().or(header.vrf_result_0.as_ref()).map(hex::encode).unwrap_or_default(),
                         platform: "cncli".to_string(),
                     },
                 }
@@ -0,0 +1,54 @@
+/**
+© 2020 PERLUR Group
+
+SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
+
+*/
+
+use cardano_ouroboros_network::{
+    mux,
+    protocols::handshake::VersionTable,
+};
+use std::env;
+use log::{info, error};
+use futures::future::join_all;
+
+mod common;
+
+async fn query(host: &str, port: u16, magic: u32) -> Result<VersionTable, String> {
+    info!("Querying host {} port {} magic {}.", host, port, magic);
+    let channel = match mux::tcp::connect(host, port).await {
+        Ok(channel) => channel,
+        Err(_) => { return Err("Could not connect.".to_string()) }
+    };
+    Ok(channel.query_versions(magic).await?)
+}
+
+#[tokio::main]
+async fn main() {
+    let cfg = common::init();
+    let port = cfg.port;
+    let magic = cfg.magic;
+
+    let mut args: Vec<String> = env::args().collect();
+
+    args.remove(0);
+
+    /* Use configured host by default. */
+    if args.is_empty() {
+        args = vec![cfg.host.clone()];
+    }
+
+    join_all(args.iter().map(|host| async move {
+        match query(host, port, magic).await {
+            Ok(versions) => {
+                for (version, data) in versions {
+                    info!("Query {}:{} version {}: {:?}", &host, port, version, data);
+                }
+            }
+            Err(error) => {
+                error!("Query {}:{} failed! : {:?}", &host, port, error);
+            }
+        }
+    })).await;
+}
@@ -7,24 +7,46 @@ SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 
 use cardano_ouroboros_network::{
     mux,
-    protocols::blockfetch::{BlockFetchProtocol, Mode},
+    protocols::blockfetch::{BlockFetchProtocol, Listener},
+    Point,
 };
-use futures::executor::block_on;
+use std::{env, process};
+use log::info;
 
 mod common;
 mod sqlite;
 
-fn main() {
+struct Blocks;
+
+impl Listener for Blocks {
+    fn handle_block(&mut self, block: &[u8]) {
+        info!("Received block of {} bytes: {}", block.len(), hex::encode(block));
+    }
+}
+
+fn parse_point(slot: &str, hash: &str) -> Point {
+    (slot.parse().expect("invalid slot"), hex::decode(hash).expect("invalid hash"))
+}
+
+#[tokio::main]
+async fn main() {
     let cfg = common::init();
 
-    block_on(async {
-        let channel = mux::tcp::connect(&cfg.host, cfg.port).await.unwrap();
-        channel.handshake(cfg.magic).await.unwrap();
-        channel.execute({BlockFetchProtocol {
-            mode: Mode::Receive,
-            network_magic: cfg.magic,
-            store: Some(Box::new(sqlite::SQLiteBlockStore::new(&cfg.db).unwrap())),
-            ..Default::default()
-        }}).await.unwrap();
-    });
-}
\ No newline at end of file
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 5 {
+        eprintln!("Usage: {} <first slot> <first hash> <last slot> <last hash>", args[0]);
+        process::exit(1);
+    }
+    let first = parse_point(&args[1], &args[2]);
+    let last = parse_point(&args[3], &args[4]);
+
+    let channel = mux::tcp::connect(&cfg.host, cfg.port).await.unwrap();
+    channel.handshake(cfg.magic).await.unwrap();
+    let result = channel.execute(BlockFetchProtocol {
+        network_magic: cfg.magic,
+        store: Some(Box::new(sqlite::SQLiteBlockStore::new(&cfg.db).unwrap())),
+        notify: Some(Box::new(Blocks)),
+        ..BlockFetchProtocol::range(first, last)
+    }).await.unwrap();
+    info!("{}", result);
+}
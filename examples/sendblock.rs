@@ -6,25 +6,73 @@ SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 */
 
 use cardano_ouroboros_network::{
-    mux,
-        protocols::blockfetch::{BlockFetchProtocol, Mode},
+    mux::tcp::Channel,
+    protocols::{
+        blockfetch::BlockFetchProtocol,
+        handshake::HandshakeProtocol,
+    },
+    BlockBodyStore,
+    Point,
 };
-use futures::executor::block_on;
+use std::{
+    fs,
+    path::PathBuf,
+};
+use tokio::net::{TcpListener, TcpStream};
+use log::{info, error};
 
 mod common;
-mod sqlite;
 
-fn main() {
+/* Serves blocks stored as files named <slot>.<hash>.cbor */
+struct FileBlockStore {
+    dir: PathBuf,
+}
+
+impl FileBlockStore {
+    fn points(&self) -> Vec<(Point, PathBuf)> {
+        let mut points: Vec<(Point, PathBuf)> = fs::read_dir(&self.dir).into_iter().flatten()
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let name = path.file_name()?.to_str()?.strip_suffix(".cbor")?.to_string();
+                let (slot, hash) = name.split_once('.')?;
+                Some(((slot.parse().ok()?, hex::decode(hash).ok()?), path))
+            })
+            .collect();
+        points.sort();
+        points
+    }
+}
+
+impl BlockBodyStore for FileBlockStore {
+    fn load_block_bodies(&mut self, first: &Point, last: &Point) -> Option<Vec<Vec<u8>>> {
+        let points = self.points();
+        let start = points.iter().position(|(point, _)| point == first)?;
+        let end = points.iter().position(|(point, _)| point == last)?;
+        points[start..=end].iter().map(|(_, path)| fs::read(path).ok()).collect()
+    }
+}
+
+#[tokio::main]
+async fn main() {
     let cfg = common::init();
+    let listener = TcpListener::bind(format!("127.0.0.1:{}", cfg.port)).await.unwrap();
+
+    loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        match handle(stream, &cfg).await {
+            Ok(result) => info!("connection closed: {}", result),
+            Err(e) => error!("connection failed: {}", e),
+        }
+    }
+}
+
+async fn handle(stream: TcpStream, cfg: &common::Config) -> Result<String, String> {
+    let channel = Channel::new(stream);
 
-    block_on(async {
-        let channel = mux::tcp::connect(&cfg.host, cfg.port).await.unwrap();
-        channel.handshake(cfg.magic).await.unwrap();
-        channel.execute({BlockFetchProtocol {
-            mode: Mode::Send,
-            network_magic: cfg.magic,
-            store: Some(Box::new(sqlite::SQLiteBlockStore::new(&cfg.db).unwrap())),
-            ..Default::default()
-        }}).await.unwrap();
-    });
-}
\ No newline at end of file
+    info!("new client!");
+    channel.execute(HandshakeProtocol::expect(cfg.magic)).await?;
+    channel.execute(BlockFetchProtocol {
+        network_magic: cfg.magic,
+        ..BlockFetchProtocol::serve(Box::new(FileBlockStore { dir: PathBuf::from("blocks") }))
+    }).await
+}
@@ -12,31 +12,32 @@ use cardano_ouroboros_network::{
         pingpong,
     },
 };
-use std::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, TcpStream};
 use log::{info, error};
-use futures::executor::block_on;
 
 mod common;
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let cfg = common::init();
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", cfg.port)).unwrap();
+    let listener = TcpListener::bind(format!("127.0.0.1:{}", cfg.port)).await.unwrap();
 
-    for stream in listener.incoming() {
-        match handle(stream.unwrap(), &cfg) {
+    loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        match handle(stream, &cfg).await {
             Ok(_) => info!("connection closed"),
             Err(e) => error!("connection failed: {}", e),
         }
     }
 }
 
-fn handle(stream: TcpStream, cfg: &common::Config) -> Result<(), String> {
+async fn handle(stream: TcpStream, cfg: &common::Config) -> Result<(), String> {
     let channel = Channel::new(stream);
 
     info!("new client!");
-    block_on(async {
-        channel.execute(handshake::HandshakeProtocol::expect(cfg.magic)).await?;
-        channel.execute(pingpong::PingPongProtocol::expect(0x0100)).await?;
-        Ok(())
-    })
+    /* PingPong is no well known mini-protocol, it has to be started before the client speaks. */
+    let pingpong = channel.start(pingpong::PingPongProtocol::expect(0x0100));
+    channel.execute(handshake::HandshakeProtocol::expect(cfg.magic)).await?;
+    pingpong.await?;
+    Ok(())
 }
@@ -18,6 +18,7 @@ use blake2b_simd::Params;
 use rusqlite::{Connection, Error, named_params};
 use cardano_ouroboros_network::{
     BlockStore,
+    Point,
     block::BlockHeader,
 };
 
@@ -26,7 +27,7 @@ pub struct SQLiteBlockStore {
 }
 
 impl SQLiteBlockStore {
-    const DB_VERSION: i64 = 2;
+    const DB_VERSION: i64 = 3;
 
     pub fn new(db_path: &PathBuf) -> Result<SQLiteBlockStore, Error> {
         debug!("Opening database");
@@ -90,6 +91,14 @@ impl SQLiteBlockStore {
                 )", [])?;
             }
 
+            // Upgrade their database to version 3
+            if version < 3 {
+                debug!("Upgrade database to version 3...");
+                // Praos headers have a single VRF result instead of the nonce and leader ones
+                db.execute("ALTER TABLE chain ADD COLUMN vrf_result_0 TEXT NOT NULL DEFAULT ''", [])?;
+                db.execute("ALTER TABLE chain ADD COLUMN vrf_result_1 TEXT NOT NULL DEFAULT ''", [])?;
+            }
+
             // Update the db version now that we've upgraded the user's database fully
             if version < 0 {
                 db.execute("INSERT INTO db_version (version) VALUES (?1)", &[&SQLiteBlockStore::DB_VERSION])?;
@@ -138,6 +147,8 @@ impl SQLiteBlockStore {
             eta_vrf_1, \
             leader_vrf_0, \
             leader_vrf_1, \
+            vrf_result_0, \
+            vrf_result_1, \
             block_size, \
             block_body_hash, \
             pool_opcert, \
@@ -158,6 +169,8 @@ impl SQLiteBlockStore {
             :eta_vrf_1, \
             :leader_vrf_0, \
             :leader_vrf_1, \
+            :vrf_result_0, \
+            :vrf_result_1, \
             :block_size, \
             :block_body_hash, \
             :pool_opcert, \
@@ -190,8 +203,14 @@ impl SQLiteBlockStore {
                         ).unwrap()
                     };
                 }
-                // blake2b hash of eta_vrf_0
-                let mut block_eta_v = Params::new().hash_length(32).to_state().update(&*block.eta_vrf_0).finalize().as_bytes().to_vec();
+                let hash = |bytes: &[u8]| Params::new().hash_length(32).to_state().update(bytes).finalize().as_bytes().to_vec();
+                let mut block_eta_v = match (&block.eta_vrf_0, &block.vrf_result_0) {
+                    // TPraos: blake2b hash of eta_vrf_0
+                    (Some(eta_vrf_0), _) => hash(eta_vrf_0),
+                    // Praos: the nonce is derived from the VRF result tagged with "N", hashed twice
+                    (None, Some(vrf_result_0)) => hash(&hash(&[b"N", &vrf_result_0[..]].concat())),
+                    (None, None) => return Err(Error::ToSqlConversionFailure("header without VRF result".into())),
+                };
                 prev_eta_v.append(&mut block_eta_v);
                 // blake2b hash of prev_eta_v + block_eta_v
                 prev_eta_v = Params::new().hash_length(32).to_state().update(&*prev_eta_v).finalize().as_bytes().to_vec();
@@ -205,10 +224,12 @@ impl SQLiteBlockStore {
                     ":eta_v" : hex::encode(&prev_eta_v),
                     ":node_vkey" : hex::encode(block.node_vkey),
                     ":node_vrf_vkey" : hex::encode(block.node_vrf_vkey),
-                    ":eta_vrf_0" : hex::encode(block.eta_vrf_0),
-                    ":eta_vrf_1" : hex::encode(block.eta_vrf_1),
-                    ":leader_vrf_0" : hex::encode(block.leader_vrf_0),
-                    ":leader_vrf_1" : hex::encode(block.leader_vrf_1),
+                    ":eta_vrf_0" : hex::encode(block.eta_vrf_0.unwrap_or_default()),
+                    ":eta_vrf_1" : hex::encode(block.eta_vrf_1.unwrap_or_default()),
+                    ":leader_vrf_0" : hex::encode(block.leader_vrf_0.unwrap_or_default()),
+                    ":leader_vrf_1" : hex::encode(block.leader_vrf_1.unwrap_or_default()),
+                    ":vrf_result_0" : hex::encode(block.vrf_result_0.unwrap_or_default()),
+                    ":vrf_result_1" : hex::encode(block.vrf_result_1.unwrap_or_default()),
                     ":block_size" : block.block_size,
                     ":block_body_hash" : hex::encode(block.block_body_hash),
                     ":pool_opcert" : hex::encode(block.pool_opcert),
@@ -248,4 +269,12 @@ impl BlockStore for SQLiteBlockStore {
         }).ok()?;
         Some(blocks.map(|item| item.unwrap()).collect())
     }
+
+    fn rollback(&mut self, point: Option<&Point>) -> io::Result<()> {
+        let slot = point.map_or(-1, |(slot, _)| *slot);
+        match self.db.execute("UPDATE chain SET orphaned = 1 WHERE slot_number > ?1", [slot]) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Database error!")),
+        }
+    }
 }
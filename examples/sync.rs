@@ -7,24 +7,31 @@ SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 
 use cardano_ouroboros_network::{
     mux,
-    protocols::chainsync::{ChainSyncProtocol, Mode},
+    protocols::{
+        chainsync::{ChainSyncProtocol, Mode},
+        keepalive::KeepAliveProtocol,
+    },
 };
-use futures::executor::block_on;
 
 mod common;
 mod sqlite;
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let cfg = common::init();
 
-    block_on(async {
-        let channel = mux::tcp::connect(&cfg.host, cfg.port).await.unwrap();
-        channel.handshake(cfg.magic).await.unwrap();
-        channel.execute({ChainSyncProtocol {
-            mode: Mode::Sync,
-            network_magic: cfg.magic,
-            store: Some(Box::new(sqlite::SQLiteBlockStore::new(&cfg.db).unwrap())),
-            ..Default::default()
-        }}).await.unwrap();
-    });
+    let channel = mux::tcp::connect(&cfg.host, cfg.port).await.unwrap();
+    channel.handshake(cfg.magic).await.unwrap();
+    let sync = channel.execute({ChainSyncProtocol {
+        mode: Mode::Sync,
+        network_magic: cfg.magic,
+        store: Some(Box::new(sqlite::SQLiteBlockStore::new(&cfg.db).unwrap())),
+        ..Default::default()
+    }});
+    /* Relays drop idle connections, keep-alives run as long as the sync does. */
+    let keep_alive = channel.execute(KeepAliveProtocol::new());
+    tokio::select! {
+        result = sync => { result.unwrap(); }
+        result = keep_alive => { result.unwrap(); }
+    }
 }
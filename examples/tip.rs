@@ -10,9 +10,6 @@ use cardano_ouroboros_network::{
     mux,
     protocols::chainsync::{ChainSyncProtocol, Mode, Listener},
 };
-use futures::{
-    executor::block_on,
-};
 use log::info;
 
 mod common;
@@ -25,17 +22,16 @@ impl Listener for Handler {
     }
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let cfg = common::init();
 
-    block_on(async {
-        let channel = mux::tcp::connect(&cfg.host, cfg.port).await.unwrap();
-        channel.handshake(cfg.magic).await.unwrap();
-        channel.execute(ChainSyncProtocol {
-            mode: Mode::SendTip,
-            network_magic: cfg.magic,
-            notify: Some(Box::new(Handler {})),
-            ..Default::default()
-        }).await.unwrap();
-    });
+    let channel = mux::tcp::connect(&cfg.host, cfg.port).await.unwrap();
+    channel.handshake(cfg.magic).await.unwrap();
+    channel.execute(ChainSyncProtocol {
+        mode: Mode::SendTip,
+        network_magic: cfg.magic,
+        notify: Some(Box::new(Handler {})),
+        ..Default::default()
+    }).await.unwrap();
 }
@@ -11,6 +11,11 @@ SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 
 use log::{debug, error, info, trace, warn};
 use serde_cbor::{de, Deserializer, ser, Value};
+use blake2b_simd::Params;
+use serde::de::IgnoredAny;
+
+/* Slots per Byron epoch (10k) on mainnet and testnet. */
+pub const BYRON_EPOCH_SLOTS: i64 = 21600;
 
 pub trait Block {
 //	fn parse_block (data: Vec<u8>) -> Option<Block>;	// parse a complete block
@@ -27,18 +32,65 @@ pub struct Block {
 }
 */
 
+// Era of a block, given by its index in the hard fork combinator.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Era {
+    Byron,
+    Shelley,
+    Allegra,
+    Mary,
+    Alonzo,
+    Babbage,
+    Conway,
+}
+
+impl Era {
+    pub fn from_index(index: i128) -> Option<Era> {
+        match index {
+            0 => Some(Era::Byron),
+            1 => Some(Era::Shelley),
+            2 => Some(Era::Allegra),
+            3 => Some(Era::Mary),
+            4 => Some(Era::Alonzo),
+            5 => Some(Era::Babbage),
+            6 => Some(Era::Conway),
+            _ => None,
+        }
+    }
+
+    // Era of a block received with BlockFetch, whose tag counts the Byron
+    // epoch boundary (0) and main blocks (1) separately.
+    pub fn from_block_tag(tag: i128) -> Option<Era> {
+        match tag {
+            0 | 1 => Some(Era::Byron),
+            2 => Some(Era::Shelley),
+            3 => Some(Era::Allegra),
+            4 => Some(Era::Mary),
+            5 => Some(Era::Alonzo),
+            6 => Some(Era::Babbage),
+            7 => Some(Era::Conway),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BlockHeader {
+    pub era: Era,
     pub block_number: i64,
     pub slot_number: i64,
     pub hash: Vec<u8>,
     pub prev_hash: Vec<u8>,
     pub node_vkey: Vec<u8>,
     pub node_vrf_vkey: Vec<u8>,
-    pub eta_vrf_0: Vec<u8>,
-    pub eta_vrf_1: Vec<u8>,
-    pub leader_vrf_0: Vec<u8>,
-    pub leader_vrf_1: Vec<u8>,
+    /* TPraos (up to Alonzo): nonce and leader VRF results, None since Babbage. */
+    pub eta_vrf_0: Option<Vec<u8>>,
+    pub eta_vrf_1: Option<Vec<u8>>,
+    pub leader_vrf_0: Option<Vec<u8>>,
+    pub leader_vrf_1: Option<Vec<u8>>,
+    /* Praos (since Babbage): single VRF result both are derived from, None before. */
+    pub vrf_result_0: Option<Vec<u8>>,
+    pub vrf_result_1: Option<Vec<u8>>,
     pub block_size: i64,
     pub block_body_hash: Vec<u8>,
     pub pool_opcert: Vec<u8>,
@@ -48,3 +100,350 @@ pub struct BlockHeader {
     pub protocol_major_version: i64,
     pub protocol_minor_version: i64,
 }
+
+fn integer(value: Option<&Value>) -> Option<i64> {
+    match value {
+        Some(Value::Integer(integer)) => Some(*integer as i64),
+        _ => None,
+    }
+}
+
+fn bytes(value: Option<&Value>) -> Option<Vec<u8>> {
+    match value {
+        Some(Value::Bytes(bytes)) => Some(bytes.clone()),
+        _ => None,
+    }
+}
+
+fn array(value: Option<&Value>) -> Option<&Vec<Value>> {
+    match value {
+        Some(Value::Array(array)) => Some(array),
+        _ => None,
+    }
+}
+
+// Parse the CBOR of a Shelley based header, which is hashed as is.
+//
+// Up to Alonzo (TPraos) the header body has separate nonce and leader VRF
+// results and 15 flat fields. Since Babbage (Praos) there is a single VRF
+// result, the operational certificate is nested and the protocol version is
+// an array.
+pub fn parse_header(era: Era, header_bytes: &[u8]) -> Option<BlockHeader> {
+    let header: Value = de::from_slice(header_bytes).ok()?;
+    let body = array(array(Some(&header))?.first())?;
+
+    /* Both parts of each VRF result, or None if the era does not have it. */
+    let vrf = |index: usize| -> Option<Option<(Vec<u8>, Vec<u8>)>> {
+        let result = array(body.get(index))?;
+        Some(Some((bytes(result.first())?, bytes(result.get(1))?)))
+    };
+    let (nonce_vrf, leader_vrf, vrf_result, next) = match era {
+        Era::Byron => return None,
+        Era::Shelley | Era::Allegra | Era::Mary | Era::Alonzo => (vrf(5)?, vrf(6)?, None, 7),
+        Era::Babbage | Era::Conway => (None, None, vrf(5)?, 6),
+    };
+    let (eta_vrf_0, eta_vrf_1) = nonce_vrf.unzip();
+    let (leader_vrf_0, leader_vrf_1) = leader_vrf.unzip();
+    let (vrf_result_0, vrf_result_1) = vrf_result.unzip();
+    let (opcert, protocol_version) = match era {
+        Era::Babbage | Era::Conway => (array(body.get(8))?.clone(), array(body.get(9))?.clone()),
+        _ => (body.get(9..13)?.to_vec(), body.get(13..15)?.to_vec()),
+    };
+
+    Some(BlockHeader {
+        era,
+        block_number: integer(body.first())?,
+        slot_number: integer(body.get(1))?,
+        hash: Params::new().hash_length(32).to_state().update(header_bytes).finalize().as_bytes().to_vec(),
+        /* Null for the first block after genesis. */
+        prev_hash: bytes(body.get(2)).unwrap_or_default(),
+        node_vkey: bytes(body.get(3))?,
+        node_vrf_vkey: bytes(body.get(4))?,
+        eta_vrf_0,
+        eta_vrf_1,
+        leader_vrf_0,
+        leader_vrf_1,
+        vrf_result_0,
+        vrf_result_1,
+        block_size: integer(body.get(next))?,
+        block_body_hash: bytes(body.get(next + 1))?,
+        pool_opcert: bytes(opcert.first())?,
+        unknown_0: integer(opcert.get(1))?,
+        unknown_1: integer(opcert.get(2))?,
+        unknown_2: bytes(opcert.get(3))?,
+        protocol_major_version: integer(protocol_version.first())?,
+        protocol_minor_version: integer(protocol_version.get(1))?,
+    })
+}
+
+// Tag and header bytes of a block as received with BlockFetch: [tag, [header, ...]]
+fn split_block(block: &[u8]) -> Option<(u8, &[u8])> {
+    /* Both arrays and the tag are encoded in a single byte. */
+    match block {
+        [0x82, tag, 0x80..=0x97, ..] if *tag < 0x18 => {
+            let mut values = Deserializer::from_slice(&block[3..]).into_iter::<IgnoredAny>();
+            values.next()?.ok()?;
+            Some((*tag, &block[3..3 + values.byte_offset()]))
+        }
+        _ => None,
+    }
+}
+
+// Parse the header of a Shelley based block as received with BlockFetch,
+// None for Byron blocks, see parse_byron_block_header().
+pub fn parse_block_header(block: &[u8]) -> Option<BlockHeader> {
+    let (tag, header) = split_block(block)?;
+    match Era::from_block_tag(tag as i128)? {
+        Era::Byron => None,
+        era => parse_header(era, header),
+    }
+}
+
+// Parse the header of a Byron block as received with BlockFetch.
+pub fn parse_byron_block_header(block: &[u8]) -> Option<ByronHeader> {
+    let (tag, header) = split_block(block)?;
+    if Era::from_block_tag(tag as i128)? != Era::Byron {
+        return None;
+    }
+    /* Tag 0 or 1 is the kind ChainSync wraps the header with. */
+    parse_byron_header(&Value::Array(vec![
+        Value::Array(vec![Value::Integer(tag as i128), Value::Integer(header.len() as i128)]),
+        Value::Bytes(header.to_vec()),
+    ]))
+}
+
+// Header of a Byron main or epoch boundary block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ByronHeader {
+    pub epoch_boundary: bool,
+    pub epoch: i64,
+    pub block_number: i64,
+    pub slot_number: i64,
+    pub hash: Vec<u8>,
+    pub prev_hash: Vec<u8>,
+}
+
+// Parse a Byron header as wrapped in ChainSync: [[kind, size], #6.24(bytes)]
+// with kind 0 for epoch boundary and 1 for main blocks.
+pub fn parse_byron_header(wrapped_header: &Value) -> Option<ByronHeader> {
+    let (kind, bytes) = match wrapped_header {
+        Value::Array(wrapped) => match (wrapped.first(), wrapped.get(1)) {
+            (Some(Value::Array(context)), Some(Value::Bytes(bytes))) => match context.first() {
+                Some(Value::Integer(kind)) if *kind == 0 || *kind == 1 => (*kind as u8, bytes),
+                _ => return None,
+            },
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    /* Hash covers the header wrapped with its kind. */
+    let mut hashed = vec![0x82, kind];
+    hashed.extend(bytes);
+    let hash = Params::new().hash_length(32).to_state().update(&hashed).finalize().as_bytes().to_vec();
+
+    let header: Value = de::from_slice(bytes).ok()?;
+    let header = match header {
+        Value::Array(header) => header,
+        _ => return None,
+    };
+    let prev_hash = match header.get(1) {
+        Some(Value::Bytes(prev_hash)) => prev_hash.clone(),
+        _ => return None,
+    };
+    let consensus = match header.get(3) {
+        Some(Value::Array(consensus)) => consensus,
+        _ => return None,
+    };
+    let difficulty = |value: Option<&Value>| match value {
+        Some(Value::Array(difficulty)) => match difficulty.first() {
+            Some(Value::Integer(block_number)) => Some(*block_number as i64),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    match kind {
+        /* Epoch boundary: [epoch, [difficulty]] */
+        0 => {
+            let epoch = match consensus.first() {
+                Some(Value::Integer(epoch)) => *epoch as i64,
+                _ => return None,
+            };
+            Some(ByronHeader {
+                epoch_boundary: true,
+                epoch,
+                block_number: difficulty(consensus.get(1))?,
+                slot_number: epoch * BYRON_EPOCH_SLOTS,
+                hash,
+                prev_hash,
+            })
+        }
+        /* Main: [[epoch, slot], pubkey, [difficulty], signature] */
+        _ => {
+            let (epoch, slot) = match consensus.first() {
+                Some(Value::Array(slot_id)) => match (slot_id.first(), slot_id.get(1)) {
+                    (Some(Value::Integer(epoch)), Some(Value::Integer(slot))) => (*epoch as i64, *slot as i64),
+                    _ => return None,
+                },
+                _ => return None,
+            };
+            Some(ByronHeader {
+                epoch_boundary: false,
+                epoch,
+                block_number: difficulty(consensus.get(2))?,
+                slot_number: epoch * BYRON_EPOCH_SLOTS + slot,
+                hash,
+                prev_hash,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* Byron header with just the fields parsed. */
+    fn byron_header(consensus: Value) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![
+            Value::Integer(764824073),
+            Value::Bytes(vec![1; 32]),
+            Value::Array(vec![]),
+            consensus,
+            Value::Array(vec![]),
+        ])).unwrap()
+    }
+
+    fn wrap(kind: i128, header: &[u8]) -> Value {
+        Value::Array(vec![
+            Value::Array(vec![Value::Integer(kind), Value::Integer(header.len() as i128)]),
+            Value::Bytes(header.to_vec()),
+        ])
+    }
+
+    #[test]
+    fn parse_byron_main_header_works() {
+        let header = byron_header(Value::Array(vec![
+            Value::Array(vec![Value::Integer(2), Value::Integer(100)]),
+            Value::Bytes(vec![]),
+            Value::Array(vec![Value::Integer(43300)]),
+            Value::Array(vec![]),
+        ]));
+        let mut hashed = vec![0x82, 0x01];
+        hashed.extend(&header);
+
+        let parsed = parse_byron_header(&wrap(1, &header)).unwrap();
+        assert_eq!(parsed, ByronHeader {
+            epoch_boundary: false,
+            epoch: 2,
+            block_number: 43300,
+            slot_number: 43300,
+            hash: Params::new().hash_length(32).to_state().update(&hashed).finalize().as_bytes().to_vec(),
+            prev_hash: vec![1; 32],
+        });
+    }
+
+    #[test]
+    fn parse_byron_boundary_header_works() {
+        let header = byron_header(Value::Array(vec![
+            Value::Integer(3),
+            Value::Array(vec![Value::Integer(64799)]),
+        ]));
+        let parsed = parse_byron_header(&wrap(0, &header)).unwrap();
+        assert!(parsed.epoch_boundary);
+        assert_eq!((parsed.epoch, parsed.block_number, parsed.slot_number), (3, 64799, 64800));
+        assert_eq!(parse_byron_header(&wrap(2, &header)), None);
+    }
+
+    fn tpraos_header() -> Vec<u8> {
+        let bytes = |byte: u8| Value::Bytes(vec![byte; 32]);
+        ser::to_vec_packed(&Value::Array(vec![
+            Value::Array(vec![
+                Value::Integer(5000), Value::Integer(1000000), bytes(1), bytes(2), bytes(3),
+                Value::Array(vec![bytes(4), bytes(5)]), Value::Array(vec![bytes(6), bytes(7)]),
+                Value::Integer(1024), bytes(8), bytes(9), Value::Integer(10), Value::Integer(11), bytes(12),
+                Value::Integer(6), Value::Integer(0),
+            ]),
+            bytes(0),
+        ])).unwrap()
+    }
+
+    fn praos_header() -> Vec<u8> {
+        let bytes = |byte: u8| Value::Bytes(vec![byte; 32]);
+        ser::to_vec_packed(&Value::Array(vec![
+            Value::Array(vec![
+                Value::Integer(9000), Value::Integer(2000000), bytes(1), bytes(2), bytes(3),
+                Value::Array(vec![bytes(4), bytes(5)]),
+                Value::Integer(2048), bytes(8),
+                Value::Array(vec![bytes(9), Value::Integer(10), Value::Integer(11), bytes(12)]),
+                Value::Array(vec![Value::Integer(9), Value::Integer(1)]),
+            ]),
+            bytes(0),
+        ])).unwrap()
+    }
+
+    #[test]
+    fn parse_tpraos_header_works() {
+        let header = parse_header(Era::Alonzo, &tpraos_header()).unwrap();
+        assert_eq!(header.era, Era::Alonzo);
+        assert_eq!((header.block_number, header.slot_number, header.block_size), (5000, 1000000, 1024));
+        assert_eq!(header.hash, Params::new().hash_length(32).to_state().update(&tpraos_header()).finalize().as_bytes());
+        assert_eq!((header.eta_vrf_0.unwrap()[0], header.leader_vrf_1.unwrap()[0]), (4, 7));
+        assert_eq!(header.vrf_result_0, None);
+        assert_eq!((header.pool_opcert[0], header.unknown_0, header.unknown_1, header.unknown_2[0]), (9, 10, 11, 12));
+        assert_eq!((header.protocol_major_version, header.protocol_minor_version), (6, 0));
+    }
+
+    #[test]
+    fn parse_praos_header_works() {
+        let header = parse_header(Era::Conway, &praos_header()).unwrap();
+        assert_eq!(header.era, Era::Conway);
+        assert_eq!((header.block_number, header.slot_number, header.block_size), (9000, 2000000, 2048));
+        assert_eq!(header.block_body_hash, vec![8; 32]);
+        assert_eq!((header.vrf_result_0.unwrap()[0], header.vrf_result_1.unwrap()[0]), (4, 5));
+        assert_eq!((header.eta_vrf_0, header.leader_vrf_0), (None, None));
+        assert_eq!((header.pool_opcert[0], header.unknown_0, header.unknown_1, header.unknown_2[0]), (9, 10, 11, 12));
+        assert_eq!((header.protocol_major_version, header.protocol_minor_version), (9, 1));
+
+        /* Mismatching layouts are rejected rather than misread. */
+        assert!(parse_header(Era::Mary, &praos_header()).is_none());
+        assert!(parse_header(Era::Babbage, &tpraos_header()).is_none());
+    }
+
+    /* Block as sent with BlockFetch, with empty transactions. */
+    fn block(tag: u8, header: &[u8]) -> Vec<u8> {
+        let mut block = vec![0x82, tag, 0x85];
+        block.extend(header);
+        block.extend(vec![0x80, 0x80, 0xa0, 0x80]);
+        block
+    }
+
+    #[test]
+    fn parse_block_header_works() {
+        let header = parse_block_header(&block(0x06, &praos_header())).unwrap();
+        assert_eq!(header.era, Era::Babbage);
+        assert_eq!(header.hash, Params::new().hash_length(32).to_state().update(&praos_header()).finalize().as_bytes());
+        assert_eq!(parse_block_header(&block(0x07, &praos_header())).unwrap().era, Era::Conway);
+        assert_eq!(parse_block_header(&block(0x05, &tpraos_header())).unwrap().era, Era::Alonzo);
+        assert_eq!(parse_block_header(&block(0x02, &tpraos_header())).unwrap().era, Era::Shelley);
+        assert!(parse_block_header(&block(0x05, &praos_header())).is_none());
+        assert!(parse_block_header(&block(0x08, &praos_header())).is_none());
+        assert!(parse_block_header(&block(0x06, &praos_header())[..20]).is_none());
+    }
+
+    #[test]
+    fn parse_byron_block_header_works() {
+        let header = byron_header(Value::Array(vec![
+            Value::Array(vec![Value::Integer(2), Value::Integer(100)]),
+            Value::Bytes(vec![]),
+            Value::Array(vec![Value::Integer(43300)]),
+            Value::Array(vec![]),
+        ]));
+        assert!(parse_block_header(&block(0x01, &header)).is_none());
+        assert_eq!(parse_byron_block_header(&block(0x01, &header)), parse_byron_header(&wrap(1, &header)));
+        assert_eq!(parse_byron_block_header(&block(0x01, &header)).unwrap().slot_number, 43300);
+        assert!(parse_byron_block_header(&block(0x00, &header)).is_none());
+        assert!(parse_byron_block_header(&block(0x07, &praos_header())).is_none());
+    }
+}
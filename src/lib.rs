@@ -13,8 +13,14 @@ pub mod mux;
 pub mod protocols;
 pub mod block;
 
-use std::io;
-use block::BlockHeader;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+};
+use block::{BlockHeader, ByronHeader};
+use protocols::chainsync::Tip;
+use tokio::sync::watch;
 
 
 
@@ -40,8 +46,18 @@ pub trait Protocol {
 
     // Process data received from the remote server destined for this protocol
     fn receive_data(&mut self, data: Vec<u8>);
+
+    // Wakeup for a protocol that has agency but nothing to send yet, e.g. a
+    // server waiting for a new block. Without one, the protocol is only asked
+    // again when data arrives.
+    fn ready(&mut self) -> Option<Ready> {
+        None
+    }
 }
 
+// Future resolving once a protocol may have something to send
+pub type Ready = Pin<Box<dyn Future<Output = ()> + Send>>;
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum Agency {
     // Client continues
@@ -52,9 +68,53 @@ pub enum Agency {
     None,
 }
 
+// Point on the chain given by slot number and block hash
+pub type Point = (i64, Vec<u8>);
+
 pub trait BlockStore {
     fn save_block(&mut self, pending_blocks: &mut Vec<BlockHeader>, network_magic: u32) -> io::Result<()>;
     fn load_blocks(&mut self) -> Option<Vec<(i64, Vec<u8>)>>;
-}
 
+    // Byron headers, passed on apart as they lack most fields of later eras.
+    // Stores leaving this out skip the Byron era, so rollbacks and
+    // intersections can't refer back to it.
+    fn save_byron_block(&mut self, pending_blocks: &mut Vec<ByronHeader>, _network_magic: u32) -> io::Result<()> {
+        pending_blocks.clear();
+        Ok(())
+    }
+
+    // Drop all blocks after the point, None for the origin. Errors fail the
+    // sync, stores keeping the blocks of abandoned forks can leave it out.
+    fn rollback(&mut self, _point: Option<&Point>) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Serving ChainSync, stores only used by clients can leave these out.
+
+    // Tip of the stored chain, None if empty
+    fn load_tip(&mut self) -> Option<Tip> {
+        None
+    }
+
+    // Whether the point is on the stored chain
+    fn has_point(&mut self, _point: &Point) -> bool {
+        false
+    }
+
+    // Point and wrapped header (CBOR as sent in MsgRollForward) of the block
+    // following the given point, or of the first block for None
+    fn load_next_header(&mut self, _point: Option<&Point>) -> Option<(Point, Vec<u8>)> {
+        None
+    }
+
+    // Receiver notified whenever the stored chain changes, None if the store
+    // has to be polled for new headers
+    fn subscribe(&mut self) -> Option<watch::Receiver<()>> {
+        None
+    }
+}
 
+pub trait BlockBodyStore {
+    // Raw CBOR of the blocks from first to last point (inclusive), None if the range is unknown
+    fn load_block_bodies(&mut self, first: &Point, last: &Point) -> Option<Vec<Vec<u8>>>;
+}
@@ -5,4 +5,10 @@ SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 
 */
 
+mod channel;
+
 pub mod tcp;
+#[cfg(unix)]
+pub mod unix;
+
+pub use channel::{Channel, MAX_PENDING_SIZE, MAX_SDU_SIZE};
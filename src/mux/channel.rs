@@ -0,0 +1,645 @@
+/**
+© 2020 PERLUR Group
+
+SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
+
+*/
+
+use std::{
+    collections::HashMap,
+    future::{pending, Future},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use byteorder::{ByteOrder, NetworkEndian};
+use log::{log_enabled, trace};
+use serde::de::IgnoredAny;
+use serde_cbor::Deserializer;
+use tokio::{
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+
+use crate::{
+    Agency, Protocol, Ready,
+    protocols::handshake::{AcceptedVersion, HandshakeError, HandshakeProtocol, VersionTable},
+};
+
+/// Maximum payload of a single segment used by cardano-node.
+pub const MAX_SDU_SIZE: usize = 12288;
+
+/// Maximum amount of data kept for a mini-protocol that is not running.
+pub const MAX_PENDING_SIZE: usize = 65536;
+
+/* Mini-protocol numbers of node-to-node and node-to-client connections,
+ * peers may start them before we do. */
+const MINI_PROTOCOLS: [u16; 10] = [0, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+
+/// Multiplexed connection to a remote peer.
+///
+/// The bearer is served by a dedicated reader and writer task running on the
+/// tokio runtime, so any number of protocols can wait on the channel without
+/// blocking the executor.
+pub struct Channel {
+    start_time: Instant,
+    shared: Arc<Mutex<ChannelShared>>,
+    sender: UnboundedSender<(u16, Vec<u8>)>,
+    reader: JoinHandle<()>,
+}
+
+impl Channel {
+    /// Create a channel on the stream, e.g. a connected `TcpStream`.
+    ///
+    /// # Panics
+    ///
+    /// The reader and writer tasks are spawned right away, so this has to be
+    /// called from within a tokio runtime.
+    pub fn new(stream: impl AsyncRead + AsyncWrite + Send + 'static) -> Self {
+        Channel::with_sdu_size(stream, MAX_SDU_SIZE)
+    }
+
+    /// Create a channel splitting outgoing messages into segments of at most
+    /// `sdu_size` bytes of payload.
+    ///
+    /// # Panics
+    ///
+    /// Like `new()` this has to be called from within a tokio runtime, and
+    /// `sdu_size` has to be between 1 and 65535.
+    pub fn with_sdu_size(stream: impl AsyncRead + AsyncWrite + Send + 'static, sdu_size: usize) -> Self {
+        assert!(sdu_size > 0 && sdu_size <= u16::MAX as usize, "invalid SDU size");
+        let start_time = Instant::now();
+        let shared = Arc::new(Mutex::new(ChannelShared {
+            subchannels: HashMap::new(),
+            error: None,
+        }));
+        let (sender, receiver) = unbounded_channel();
+        let (rx, tx) = split(stream);
+
+        tokio::spawn(process_tx(tx, receiver, start_time, sdu_size));
+        let reader = tokio::spawn(process_rx(rx, shared.clone()));
+
+        Channel {
+            start_time,
+            shared,
+            sender,
+            reader,
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.start_time.elapsed()
+    }
+
+    /// Perform the node-to-node handshake proposing the default version table.
+    pub async fn handshake(&self, magic: u32) -> Result<AcceptedVersion, HandshakeError> {
+        self.handshake_with(HandshakeProtocol::new(magic)).await
+    }
+
+    /// Perform the node-to-client handshake.
+    pub async fn handshake_node_to_client(&self, magic: u32) -> Result<AcceptedVersion, HandshakeError> {
+        self.handshake_with(HandshakeProtocol::node_to_client(magic)).await
+    }
+
+    /// Perform a handshake set up by the caller, e.g. with a custom version table.
+    pub async fn handshake_with(&self, handshake: HandshakeProtocol) -> Result<AcceptedVersion, HandshakeError> {
+        let handshake = self.run(handshake).await.map_err(HandshakeError::Connection)?;
+        match (handshake.accepted(), handshake.error()) {
+            (Some(accepted), _) => Ok(accepted.clone()),
+            (None, Some(error)) => Err(error.clone()),
+            (None, None) => Err(HandshakeError::Protocol("no version accepted".to_string())),
+        }
+    }
+
+    /// Ask the peer for its supported versions without establishing a connection.
+    pub async fn query_versions(&self, magic: u32) -> Result<VersionTable, HandshakeError> {
+        let handshake = self.run(HandshakeProtocol::query(magic)).await.map_err(HandshakeError::Connection)?;
+        match (handshake.peer_versions(), handshake.accepted(), handshake.error()) {
+            (Some(versions), _, _) => Ok(versions.clone()),
+            (None, Some(accepted), _) => Err(HandshakeError::Protocol(format!(
+                "peer does not support version queries, accepted version {}", accepted.version
+            ))),
+            (None, None, Some(error)) => Err(error.clone()),
+            (None, None, None) => Err(HandshakeError::Protocol("no versions received".to_string())),
+        }
+    }
+
+    pub async fn execute(&self, protocol: impl Protocol + 'static) -> Result<String, String> {
+        self.start(protocol).await
+    }
+
+    /// Register a protocol on the channel and return a future driving it.
+    ///
+    /// The protocol is attached to its subchannel right away, the returned
+    /// future only has to be polled. Several of them can be awaited together
+    /// (e.g. using `tokio::join!`) to run mini-protocols side by side.
+    ///
+    /// Data for ids other than the well known mini-protocols fails the
+    /// connection, unless their protocol was started before it arrived.
+    pub fn start(&self, protocol: impl Protocol + 'static) -> impl Future<Output = Result<String, String>> {
+        let run = self.run(protocol);
+        async move { run.await?.result() }
+    }
+
+    /// Like `start()` but hand the finished protocol back, so that the caller
+    /// can inspect its typed results.
+    pub fn run<P: Protocol + 'static>(&self, mut protocol: P) -> impl Future<Output = Result<P, String>> {
+        let id = protocol.protocol_id();
+        let shared = self.shared.clone();
+        let sender = self.sender.clone();
+        let subscription = shared.lock().unwrap().subscribe(id)
+            .map(|receiver| (receiver, SubchannelGuard { id, shared: shared.clone() }));
+
+        async move {
+            let (mut receiver, _guard) = subscription?;
+            trace!("started subchannel {:04x}", id);
+
+            loop {
+                let agency = protocol.agency();
+                if agency == Agency::None {
+                    trace!("finished subchannel {:04x}", id);
+                    return Ok(protocol);
+                }
+
+                if agency == protocol.role() {
+                    if let Some(payload) = protocol.send_data() {
+                        if sender.send((id, payload)).is_err() {
+                            return Err(shared.lock().unwrap().error());
+                        }
+                        continue;
+                    }
+                    /* Nothing to say yet, sleep until the protocol is ready or the peer speaks. */
+                    let ready = protocol.ready();
+                    tokio::select! {
+                        payload = receiver.recv() => match payload {
+                            Some(payload) => protocol.receive_data(payload),
+                            None => return Err(shared.lock().unwrap().error()),
+                        },
+                        _ = wait(ready) => {}
+                    }
+                } else {
+                    match receiver.recv().await {
+                        Some(payload) => protocol.receive_data(payload),
+                        None => return Err(shared.lock().unwrap().error()),
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn wait(ready: Option<Ready>) {
+    match ready {
+        Some(ready) => ready.await,
+        None => pending().await,
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        /* The writer task ends on its own once the sender is gone. */
+        self.reader.abort();
+    }
+}
+
+struct Subchannel {
+    sender: UnboundedSender<Vec<u8>>,
+    receiver: Option<UnboundedReceiver<Vec<u8>>>,
+    buffer: Vec<u8>,
+    /* Bytes received before the protocol was started. */
+    pending: usize,
+}
+
+impl Subchannel {
+    fn new() -> Self {
+        let (sender, receiver) = unbounded_channel();
+        Subchannel {
+            sender,
+            receiver: Some(receiver),
+            buffer: Vec::new(),
+            pending: 0,
+        }
+    }
+
+    // Queue complete messages for the protocol, keeping any partial data
+    // until the rest of it arrives in the following segments.
+    fn receive(&mut self, payload: Vec<u8>) -> Result<(), String> {
+        if self.receiver.is_some() {
+            self.pending += payload.len();
+            if self.pending > MAX_PENDING_SIZE {
+                return Err(format!("more than {} bytes for a mini-protocol not running", MAX_PENDING_SIZE));
+            }
+        }
+        if payload.is_empty() {
+            /* Example-only protocols exchange empty messages. */
+            let _ = self.sender.send(payload);
+            return Ok(());
+        }
+        self.buffer.extend_from_slice(&payload);
+        for message in split_messages(&mut self.buffer) {
+            let _ = self.sender.send(message);
+        }
+        Ok(())
+    }
+}
+
+// Remove all complete CBOR messages from the front of the buffer.
+fn split_messages(buffer: &mut Vec<u8>) -> Vec<Vec<u8>> {
+    let mut messages = Vec::new();
+    while !buffer.is_empty() {
+        let mut iter = Deserializer::from_slice(&buffer[..]).into_iter::<IgnoredAny>();
+        match iter.next() {
+            Some(Ok(_)) => {
+                let length = iter.byte_offset();
+                messages.push(buffer.drain(..length).collect());
+            }
+            Some(Err(error)) if error.is_eof() => break,
+            _ => {
+                /* Let the protocol report the garbage. */
+                messages.push(std::mem::take(buffer));
+            }
+        }
+    }
+    messages
+}
+
+// Releases the subchannel when its protocol finishes or is dropped.
+struct SubchannelGuard {
+    id: u16,
+    shared: Arc<Mutex<ChannelShared>>,
+}
+
+impl Drop for SubchannelGuard {
+    fn drop(&mut self) {
+        self.shared.lock().unwrap().subchannels.remove(&self.id);
+    }
+}
+
+struct ChannelShared {
+    subchannels: HashMap<u16, Subchannel>,
+    error: Option<String>,
+}
+
+impl ChannelShared {
+    // Data may arrive before the protocol is started, so the queue is created
+    // by whichever side comes first.
+    fn subchannel(&mut self, id: u16) -> &mut Subchannel {
+        self.subchannels.entry(id).or_insert_with(Subchannel::new)
+    }
+
+    // Pass data on to the protocol of the id, unless nobody can be expected to read it.
+    fn receive(&mut self, id: u16, payload: Vec<u8>) -> Result<(), String> {
+        if !self.subchannels.contains_key(&id) && !MINI_PROTOCOLS.contains(&(id & 0x7fff)) {
+            return Err(format!("data for unknown mini-protocol {:04x}", id));
+        }
+        self.subchannel(id).receive(payload)
+    }
+
+    fn subscribe(&mut self, id: u16) -> Result<UnboundedReceiver<Vec<u8>>, String> {
+        if self.error.is_some() {
+            return Err(self.error());
+        }
+        self.subchannel(id).receiver.take()
+            .ok_or_else(|| format!("subchannel {:04x} already in use", id))
+    }
+
+    fn error(&self) -> String {
+        self.error.clone().unwrap_or_else(|| "connection closed".to_string())
+    }
+
+    fn close(&mut self, error: String) {
+        self.error = Some(error);
+        /* Dropping the senders wakes up all waiting protocols. */
+        self.subchannels.clear();
+    }
+}
+
+// Serialize a message into as many segments as needed to fit the SDU size.
+fn encode_segments(timestamp: u32, id: u16, payload: &[u8], sdu_size: usize) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(payload.len() + (payload.len() / sdu_size + 1) * 8);
+    let mut chunks: Vec<&[u8]> = payload.chunks(sdu_size).collect();
+    if chunks.is_empty() {
+        /* Empty messages still need their own segment. */
+        chunks.push(&[]);
+    }
+    for chunk in chunks {
+        let mut header = [0u8; 8];
+        NetworkEndian::write_u32(&mut header[0..4], timestamp);
+        NetworkEndian::write_u16(&mut header[4..6], id);
+        NetworkEndian::write_u16(&mut header[6..8], chunk.len() as u16);
+        msg.extend_from_slice(&header);
+        msg.extend_from_slice(chunk);
+    }
+    msg
+}
+
+async fn process_tx(mut stream: WriteHalf<impl AsyncWrite>, mut receiver: UnboundedReceiver<(u16, Vec<u8>)>, start_time: Instant, sdu_size: usize) {
+    while let Some((id, payload)) = receiver.recv().await {
+        let msg = encode_segments(start_time.elapsed().as_micros() as u32, id, &payload, sdu_size);
+        if log_enabled!(log::Level::Trace) {
+            trace!("tx bytes: {}", hex::encode(&msg));
+        }
+        if let Err(error) = stream.write_all(&msg).await {
+            trace!("tx error: {:?}", error);
+            return;
+        }
+        trace!("tx size: {}", msg.len());
+    }
+}
+
+async fn process_rx(mut stream: ReadHalf<impl AsyncRead>, shared: Arc<Mutex<ChannelShared>>) {
+    loop {
+        let mut header = [0u8; 8];
+        if let Err(error) = stream.read_exact(&mut header).await {
+            shared.lock().unwrap().close(format!("header read error: {:?}", error));
+            return;
+        }
+        let length = NetworkEndian::read_u16(&header[6..]) as usize;
+        let mut payload = vec![0u8; length];
+        if let Err(error) = stream.read_exact(&mut payload).await {
+            shared.lock().unwrap().close(format!("payload read error: {:?}", error));
+            return;
+        }
+        trace!("rx bytes: {} {}", hex::encode(header), hex::encode(&payload));
+        let _timestamp = NetworkEndian::read_u32(&header[0..4]);
+        let id = NetworkEndian::read_u16(&header[4..6]) ^ 0x8000;
+        /* Agency is left to the protocols decoding the messages, a running
+         * protocol reads whatever it gets so only idle subchannels are limited. */
+        let mut shared = shared.lock().unwrap();
+        if let Err(error) = shared.receive(id, payload) {
+            shared.close(error);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+    use crate::protocols::handshake::RefuseReason;
+
+    #[test]
+    fn encode_segments_works() {
+        assert_eq!(encode_segments(1, 2, &[], 4), vec![0, 0, 0, 1, 0, 2, 0, 0]);
+        assert_eq!(encode_segments(1, 2, &[9, 9, 9], 4), vec![0, 0, 0, 1, 0, 2, 0, 3, 9, 9, 9]);
+
+        let payload: Vec<u8> = (0..10).collect();
+        assert_eq!(encode_segments(1, 0x8002, &payload, 4), vec![
+            0, 0, 0, 1, 0x80, 2, 0, 4, 0, 1, 2, 3,
+            0, 0, 0, 1, 0x80, 2, 0, 4, 4, 5, 6, 7,
+            0, 0, 0, 1, 0x80, 2, 0, 2, 8, 9,
+        ]);
+    }
+
+    #[test]
+    fn encode_large_payload_works() {
+        let payload: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let msg = encode_segments(0, 3, &payload, MAX_SDU_SIZE);
+
+        let mut decoded = Vec::new();
+        let mut segments = 0;
+        let mut rest = &msg[..];
+        while !rest.is_empty() {
+            assert_eq!(NetworkEndian::read_u16(&rest[4..6]), 3);
+            let length = NetworkEndian::read_u16(&rest[6..8]) as usize;
+            assert!(length <= MAX_SDU_SIZE);
+            decoded.extend_from_slice(&rest[8..8 + length]);
+            rest = &rest[8 + length..];
+            segments += 1;
+        }
+        assert_eq!(segments, 17);
+        assert_eq!(decoded, payload);
+    }
+
+    #[test]
+    fn split_messages_works() {
+        let mut buffer = Vec::new();
+        assert!(split_messages(&mut buffer).is_empty());
+
+        /* [0] [1, h'0102'] and the start of [2] */
+        buffer.extend_from_slice(&[0x81, 0x00, 0x82, 0x01, 0x42, 0x01]);
+        assert_eq!(split_messages(&mut buffer), vec![vec![0x81, 0x00]]);
+        assert_eq!(buffer, vec![0x82, 0x01, 0x42, 0x01]);
+
+        buffer.extend_from_slice(&[0x02, 0x81]);
+        assert_eq!(split_messages(&mut buffer), vec![vec![0x82, 0x01, 0x42, 0x01, 0x02]]);
+        assert_eq!(buffer, vec![0x81]);
+
+        buffer.extend_from_slice(&[0x02]);
+        assert_eq!(split_messages(&mut buffer), vec![vec![0x81, 0x02]]);
+        assert!(buffer.is_empty());
+
+        /* Invalid data is passed on as is. */
+        buffer.extend_from_slice(&[0xff, 0x00]);
+        assert_eq!(split_messages(&mut buffer), vec![vec![0xff, 0x00]]);
+        assert!(buffer.is_empty());
+    }
+
+    struct Exchange {
+        role: Agency,
+        idx: u16,
+        rounds: usize,
+        busy: bool,
+    }
+
+    impl Exchange {
+        fn new(role: Agency, idx: u16, rounds: usize) -> Self {
+            Exchange { role, idx, rounds, busy: false }
+        }
+
+        fn payload(&self) -> Vec<u8> {
+            serde_cbor::to_vec(&(self.idx, vec![self.idx as u8; 100])).unwrap()
+        }
+    }
+
+    impl Protocol for Exchange {
+        fn protocol_id(&self) -> u16 {
+            match self.role {
+                Agency::Server => self.idx ^ 0x8000,
+                _ => self.idx,
+            }
+        }
+
+        fn result(&self) -> Result<String, String> {
+            Ok(format!("{:04x}", self.idx))
+        }
+
+        fn role(&self) -> Agency {
+            self.role
+        }
+
+        fn agency(&self) -> Agency {
+            match (self.rounds, self.busy) {
+                (0, _) => Agency::None,
+                (_, true) => Agency::Server,
+                (_, false) => Agency::Client,
+            }
+        }
+
+        fn state(&self) -> String {
+            format!("{} {}", self.rounds, self.busy)
+        }
+
+        fn send_data(&mut self) -> Option<Vec<u8>> {
+            if self.role == Agency::Server {
+                self.rounds -= 1;
+            }
+            self.busy = !self.busy;
+            Some(self.payload())
+        }
+
+        fn receive_data(&mut self, data: Vec<u8>) {
+            assert_eq!(data, self.payload());
+            if self.role == Agency::Client {
+                self.rounds -= 1;
+            }
+            self.busy = !self.busy;
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_protocols_work() {
+        let (client, server) = duplex(1024);
+
+        let cli = async move {
+            /* Small segments split every message, the peer reassembles them while the three protocols take turns. */
+            let client = Channel::with_sdu_size(client, 16);
+            let results = tokio::join!(
+                client.start(Exchange::new(Agency::Client, 0x0101, 10)),
+                client.start(Exchange::new(Agency::Client, 0x0102, 20)),
+                client.start(Exchange::new(Agency::Client, 0x0103, 5)),
+            );
+            assert_eq!(results, (Ok("0101".to_string()), Ok("0102".to_string()), Ok("0103".to_string())));
+        };
+        let srv = async move {
+            let server = Channel::new(server);
+            let results = tokio::join!(
+                server.start(Exchange::new(Agency::Server, 0x0101, 10)),
+                server.start(Exchange::new(Agency::Server, 0x0102, 20)),
+                server.start(Exchange::new(Agency::Server, 0x0103, 5)),
+            );
+            assert!(results.0.is_ok() && results.1.is_ok() && results.2.is_ok());
+        };
+
+        tokio::join!(cli, srv);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn spawned_protocols_work() {
+        let (client, server) = duplex(1024);
+
+        /* Data of the well known mini-protocols may arrive before the spawned server starts. */
+        let client = Channel::new(client);
+        let server = Channel::new(server);
+        let cli = tokio::spawn(async move { client.execute(Exchange::new(Agency::Client, 0x0002, 10)).await });
+        let srv = tokio::spawn(async move { server.execute(Exchange::new(Agency::Server, 0x0002, 10)).await });
+
+        assert_eq!(cli.await.unwrap(), Ok("0002".to_string()));
+        assert_eq!(srv.await.unwrap(), Ok("0002".to_string()));
+    }
+
+    #[tokio::test]
+    async fn early_data_works() {
+        let (client, mut server) = duplex(1024);
+
+        /* The peer starts ChainSync before we do. */
+        let client = Channel::new(client);
+        let exchange = Exchange::new(Agency::Server, 0x0002, 1);
+        server.write_all(&encode_segments(0, 0x8002, &exchange.payload(), MAX_SDU_SIZE)).await.unwrap();
+        tokio::task::yield_now().await;
+        assert_eq!(client.start(Exchange::new(Agency::Client, 0x0002, 1)).await, Ok("0002".to_string()));
+    }
+
+    #[tokio::test]
+    async fn unknown_protocol_fails() {
+        let (client, mut server) = duplex(1024);
+
+        let client = Channel::new(client);
+        server.write_all(&encode_segments(0, 0x8123, &[0x80], MAX_SDU_SIZE)).await.unwrap();
+        match client.start(Exchange::new(Agency::Client, 0x0101, 1)).await {
+            Err(error) => assert_eq!(error, "data for unknown mini-protocol 0123"),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_data_is_limited() {
+        let (client, mut server) = duplex(1024);
+
+        let client = Channel::new(client);
+        let segments = encode_segments(0, 0x8002, &vec![0u8; MAX_PENDING_SIZE + 1], MAX_SDU_SIZE);
+        server.write_all(&segments).await.unwrap();
+        /* Nobody reads ChainSync, the connection fails instead of buffering on. */
+        let wait = async {
+            while client.shared.lock().unwrap().error.is_none() {
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), wait).await.unwrap();
+        assert!(client.shared.lock().unwrap().subchannels.is_empty());
+        assert!(client.start(Exchange::new(Agency::Client, 0x0002, 1)).await.unwrap_err().contains("not running"));
+    }
+
+    #[tokio::test]
+    async fn connection_closed_works() {
+        let (client, server) = duplex(1024);
+
+        let client = Channel::new(client);
+        drop(server);
+        match client.handshake(764824073).await {
+            Err(HandshakeError::Connection(error)) => assert!(error.contains("read error")),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(client.handshake(764824073).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn handshake_refused_works() {
+        let (client, server) = duplex(1024);
+
+        let cli = async move {
+            let client = Channel::new(client);
+            match client.handshake(1).await {
+                Err(HandshakeError::Refused(RefuseReason::Refused(version, _))) => assert_eq!(version, 14),
+                result => panic!("unexpected result {:?}", result),
+            }
+        };
+        let srv = async move {
+            let server = Channel::new(server);
+            assert!(server.execute(HandshakeProtocol::expect(2)).await.is_err());
+        };
+
+        tokio::join!(cli, srv);
+    }
+
+    #[tokio::test]
+    async fn query_versions_works() {
+        let (client, server) = duplex(1024);
+
+        let cli = async move {
+            let client = Channel::new(client);
+            let versions = client.query_versions(764824073).await.unwrap();
+            assert_eq!(versions.keys().next_back(), Some(&14));
+            assert_eq!(versions[&14].network_magic, 2);
+        };
+        let srv = async move {
+            let server = Channel::new(server);
+            assert_eq!(server.execute(HandshakeProtocol::expect(2)).await.unwrap(), "queried");
+        };
+
+        tokio::join!(cli, srv);
+    }
+
+    #[tokio::test]
+    async fn subchannel_is_exclusive() {
+        let (client, _server) = duplex(1024);
+
+        let client = Channel::new(client);
+        let first = client.start(Exchange::new(Agency::Client, 0x0101, 1));
+        assert!(client.start(Exchange::new(Agency::Client, 0x0101, 1)).await.is_err());
+        drop(first);
+        assert!(client.shared.lock().unwrap().subscribe(0x0101).is_ok());
+    }
+}
@@ -0,0 +1,47 @@
+/**
+© 2020 PERLUR Group
+
+SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
+
+*/
+
+use std::{
+    io,
+    path::Path,
+};
+
+use tokio::net::UnixStream;
+
+pub use super::channel::Channel;
+
+/// Connect to the local socket of a node, e.g. `node.socket`.
+pub async fn connect(path: impl AsRef<Path>) -> io::Result<Channel> {
+    let stream = UnixStream::connect(path).await?;
+    Ok(Channel::new(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixListener;
+    use crate::protocols::handshake::HandshakeProtocol;
+
+    #[tokio::test]
+    async fn connection_works() {
+        let path = std::env::temp_dir().join(format!("cardano-ouroboros-network-{}.socket", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let cli = async {
+            let client = connect(&path).await.unwrap();
+            client.handshake(764824073).await.unwrap();
+        };
+        let srv = async {
+            let server = Channel::new(listener.accept().await.unwrap().0);
+            server.execute(HandshakeProtocol::expect(764824073)).await.unwrap();
+        };
+
+        tokio::join!(cli, srv);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
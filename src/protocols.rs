@@ -15,6 +15,8 @@ pub mod transaction;
 pub mod transaction2;
 pub mod chainsync;
 pub mod blockfetch;
+pub mod keepalive;
+pub mod peersharing;
 
 /* example-only protocols */
 pub mod pingpong;
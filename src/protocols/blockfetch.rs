@@ -4,27 +4,21 @@ SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 
 */
 
+use std::collections::VecDeque;
 
-/*use std::{
-    time::{Duration, Instant},
-    io,
-    ops::Sub,
-};*/
-
-use log::{debug, error, warn};
-use serde_cbor::{de, Deserializer, Value};
-
-//use blake2b_simd::Params;
+use log::{debug, error, trace, warn};
+use serde_cbor::{Deserializer, ser, Value};
 
 use crate::{
     Agency,
     Protocol,
+    BlockBodyStore,
+    BlockHeader,
     BlockStore,
-	BlockHeader,
+    Point,
+    block::{parse_block_header, parse_byron_block_header, ByronHeader},
 };
 
-
-
 #[derive(Debug)]
 pub enum State {
     Idle,
@@ -33,54 +27,197 @@ pub enum State {
     Done,
 }
 
-
 #[derive(PartialEq)]
 pub enum Mode {
     Receive,
     Send,
 }
 
+pub trait Listener {
+    // Raw CBOR of each block received
+    fn handle_block(&mut self, block: &[u8]);
+}
+
 pub struct BlockFetchProtocol {
-	pub mode: Mode,
+    pub mode: Mode,
     pub state: State,
     pub result: Option<Result<String, String>>,
     pub store: Option<Box<dyn BlockStore>>,
+    /* Server: source of the blocks sent. */
+    pub body_store: Option<Box<dyn BlockBodyStore>>,
     pub network_magic: u32,
     pub pending_blocks: Vec<BlockHeader>,
-	pub received_request: bool,
-	pub sent_request: bool,
+    pub pending_byron_blocks: Vec<ByronHeader>,
+    pub request: Option<(Point, Point)>,
+    /* Ranges from first to last point (inclusive) still to be requested. */
+    pub ranges: Vec<(Point, Point)>,
+    pub notify: Option<Box<dyn Listener>>,
+    pub received_blocks: usize,
+    /* Blocks of the current batch still to be sent. */
+    pub batch: VecDeque<Vec<u8>>,
+    pub sent_blocks: usize,
 }
 
 impl Default for BlockFetchProtocol {
     fn default() -> Self {
-        BlockFetchProtocol { 
-			mode: Mode::Receive,
-			state: State::Idle, 
-			result: None, 
-			store: None,
-			network_magic: 764824073,
-			pending_blocks: Vec::new(),
-			received_request: false, 
-			sent_request: false }
+        BlockFetchProtocol {
+            mode: Mode::Receive,
+            state: State::Idle,
+            result: None,
+            store: None,
+            body_store: None,
+            network_magic: 764824073,
+            pending_blocks: Vec::new(),
+            pending_byron_blocks: Vec::new(),
+            request: None,
+            ranges: Vec::new(),
+            notify: None,
+            received_blocks: 0,
+            batch: VecDeque::new(),
+            sent_blocks: 0,
+        }
     }
 }
 
 impl BlockFetchProtocol {
-/*    fn save_block(&mut self, parse_msg_block: &BlockHeader) -> io::Result<()> {		// TODO: this is just a copy from chainsync!
-        match self.store.as_mut() {
-            Some(store) => {
-                self.pending_blocks.push((*parse_msg_block).clone());
+    // Client fetching all blocks from first to last point.
+    pub fn range(first: Point, last: Point) -> Self {
+        BlockFetchProtocol {
+            ranges: vec![(first, last)],
+            ..Default::default()
+        }
+    }
+
+    // Server answering requests with blocks from the store.
+    pub fn serve(store: Box<dyn BlockBodyStore>) -> Self {
+        BlockFetchProtocol {
+            mode: Mode::Send,
+            body_store: Some(store),
+            ..Default::default()
+        }
+    }
+
+    fn msg_request_range(&self, (first, last): &(Point, Point)) -> Vec<u8> {
+        let point = |(slot, hash): &Point| Value::Array(vec![
+            Value::Integer(*slot as i128),
+            Value::Bytes(hash.clone()),
+        ]);
+        ser::to_vec_packed(&Value::Array(vec![
+            Value::Integer(0),
+            point(first),
+            point(last),
+        ])).unwrap()
+    }
+
+    fn msg_client_done(&self) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(1)])).unwrap()
+    }
+
+    fn msg_start_batch(&self) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(2)])).unwrap()
+    }
+
+    fn msg_no_blocks(&self) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(3)])).unwrap()
+    }
+
+    fn msg_block(&self, block: Vec<u8>) -> Vec<u8> {
+        /* Array of two with message id 4 and tag 24 (CBOR in CBOR), which serde_cbor only writes with the tags feature. */
+        let mut payload = vec![0x82, 0x04, 0xd8, 0x18];
+        payload.append(&mut ser::to_vec_packed(&Value::Bytes(block)).unwrap());
+        payload
+    }
+
+    fn msg_batch_done(&self) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(5)])).unwrap()
+    }
+
+    fn load_batch(&mut self) -> bool {
+        let blocks = match (self.request.take(), self.body_store.as_mut()) {
+            (Some((first, last)), Some(store)) => store.load_block_bodies(&first, &last),
+            _ => None,
+        };
+        self.batch = blocks.unwrap_or_default().into();
+        !self.batch.is_empty()
+    }
+
+    fn handle_request(&mut self, cbor_array: &[Value]) {
+        let point = |value: Option<&Value>| match value {
+            Some(Value::Array(point)) => match (point.first(), point.get(1)) {
+                (Some(Value::Integer(slot)), Some(Value::Bytes(hash))) => Some((*slot as i64, hash.clone())),
+                _ => None,
+            },
+            _ => None,
+        };
+        self.request = match (point(cbor_array.get(1)), point(cbor_array.get(2))) {
+            (Some(first), Some(last)) => Some((first, last)),
+            _ => {
+                warn!("Unexpected range: {:?}", cbor_array);
+                None
             }
-            None => {}
+        };
+    }
+
+    fn handle_block(&mut self, cbor_array: &[Value]) {
+        match cbor_array.get(1) {
+            /* Block is wrapped in CBOR tag 24, which may or may not be retained. */
+            Some(Value::Tag(24, wrapped)) => match &**wrapped {
+                Value::Bytes(block) => self.notify_block(block),
+                _ => warn!("Unexpected block: {:?}", wrapped),
+            },
+            Some(Value::Bytes(block)) => self.notify_block(block),
+            value => warn!("Unexpected block: {:?}", value),
         }
-        Ok(())
-    }*/
+    }
 
+    fn fail(&mut self, error: String) {
+        error!("{}", error);
+        self.state = State::Done;
+        self.result = Some(Err(error));
+    }
+
+    fn notify_block(&mut self, block: &[u8]) {
+        trace!("BlockFetchProtocol received block of {} bytes", block.len());
+        self.received_blocks += 1;
+        if self.store.is_some() {
+            match parse_block_header(block) {
+                Some(header) => self.pending_blocks.push(header),
+                None => match parse_byron_block_header(block) {
+                    Some(header) => self.pending_byron_blocks.push(header),
+                    None => warn!("BlockFetchProtocol not storing block, header not parsed"),
+                },
+            }
+        }
+        if let Some(listener) = &mut self.notify {
+            listener.handle_block(block);
+        }
+    }
+
+    // Store the headers of the blocks of a batch at once.
+    fn save_blocks(&mut self) {
+        if let Some(store) = self.store.as_mut() {
+            /* A batch may cross into Shelley, Byron blocks come first. */
+            let mut result = Ok(());
+            if !self.pending_byron_blocks.is_empty() {
+                result = store.save_byron_block(&mut self.pending_byron_blocks, self.network_magic);
+            }
+            if result.is_ok() {
+                result = store.save_block(&mut self.pending_blocks, self.network_magic);
+            }
+            if let Err(error) = result {
+                self.fail(format!("BlockFetchProtocol saving blocks failed: {}", error));
+            }
+        }
+    }
 }
 
 impl Protocol for BlockFetchProtocol {
     fn protocol_id(&self) -> u16 {
-        return 0x0003u16;
+        let idx: u16 = 0x0003;
+        match self.role() {
+            Agency::Server => idx ^ 0x8000,
+            _ => idx,
+        }
     }
 
     fn result(&self) -> Result<String, String> {
@@ -88,18 +225,20 @@ impl Protocol for BlockFetchProtocol {
     }
 
     fn role(&self) -> Agency {
-        Agency::Client
+        match self.mode {
+            Mode::Receive => Agency::Client,
+            Mode::Send => Agency::Server,
+        }
     }
 
     fn agency(&self) -> Agency {
-        let agency = match self.state {
+        return match self.state {
             State::Idle => { Agency::Client }
             State::Busy => { Agency::Server }
             State::Streaming => { Agency::Server }
             State::Done => { Agency::None }
-            };
-        return agency;
-        }
+        };
+    }
 
     fn state(&self) -> String {
         format!("{:?}", self.state)
@@ -109,24 +248,50 @@ impl Protocol for BlockFetchProtocol {
         return match self.state {
             State::Idle => {
                 debug!("BlockFetchProtocol::State::Idle");
-                None
+                if self.mode == Mode::Send {
+                    return None;
+                }
+                if self.ranges.is_empty() {
+                    self.state = State::Done;
+                    self.result = Some(Ok(format!("Received {} blocks", self.received_blocks)));
+                    return Some(self.msg_client_done());
+                }
+                let range = self.ranges.remove(0);
+                debug!("BlockFetchProtocol requesting range from slot {} to {}", (range.0).0, (range.1).0);
+                self.state = State::Busy;
+                Some(self.msg_request_range(&range))
             },
             State::Busy => {
                 debug!("BlockFetchProtocol::State::Busy");
-				if self.received_request {  // TODO: send MsgNoBlocks message to indicate that we have nothing to share 
-				}
-				else if self.sent_request {  // TODO: send MsgStartBatch to start getting blocks	
-				}
-				None
+                if self.mode == Mode::Receive {
+                    return None;
+                }
+                if self.load_batch() {
+                    self.state = State::Streaming;
+                    Some(self.msg_start_batch())
+                } else {
+                    self.state = State::Idle;
+                    Some(self.msg_no_blocks())
+                }
             },
             State::Streaming => {
                 debug!("BlockFetchProtocol::State::Streaming");
-//                Some(payload)
-				None				// TODO define payload
+                if self.mode == Mode::Receive {
+                    return None;
+                }
+                match self.batch.pop_front() {
+                    Some(block) => {
+                        self.sent_blocks += 1;
+                        Some(self.msg_block(block))
+                    }
+                    None => {
+                        self.state = State::Idle;
+                        Some(self.msg_batch_done())
+                    }
+                }
             },
             State::Done => {
-                warn!("BlockFetchProtocol::State::Done");
-                self.result = Option::Some(Ok(String::from("Done")));
+                debug!("BlockFetchProtocol::State::Done");
                 None
             }
         };
@@ -140,44 +305,46 @@ impl Protocol for BlockFetchProtocol {
                 Ok(cbor_value) => {
                     match cbor_value {
                         Value::Array(cbor_array) => {
-                            match cbor_array[0] {
-                                Value::Integer(message_id) => {
-                        match message_id {
-                            //msgRequestRange = [0 ,point ,point]
-                            //msgClientDone   = [1]
-                            //msgStartBatch   = [2]
-                            //msgNoBlocks     = [3]
-                            //msgBlock        = [4, #6.24(bytes.cborblock)]
-                            //msgBatchDone    = [5]
-                            0 => {
-                                debug!("BlockFetchProtocol received MsgRequestRange");
-                                self.state = State::Busy;
-								self.received_request = true
-                            }
-                            1 => {
-                                debug!("BlockFetchProtocol received MsgClientDone");
-                                self.state = State::Done
-                            }
-                            2 => {
-                                debug!("BlockFetchProtocol received MsgStartBatch");
-                                self.state = State::Streaming
-                            }
-                            3 => {
-                                debug!("BlockFetchProtocol received MsgNoBlocks");
-                                self.state = State::Idle
-                            }
-                            4 => {
-                                debug!("BlockFetchProtocol received MsgBlock");
-                                self.state = State::Done
-                            }
-                            5 => {
-                                debug!("BlockFetchProtocol received MsgBatchDone");
-                                self.state = State::Idle
-                            }
-                            _ => {
-                                error!("Got unexpected message_id: {}", message_id);
-                            }
-                       }
+                            match cbor_array.first() {
+                                Some(Value::Integer(message_id)) => {
+                                    match message_id {
+                                        //msgRequestRange = [0 ,point ,point]
+                                        //msgClientDone   = [1]
+                                        //msgStartBatch   = [2]
+                                        //msgNoBlocks     = [3]
+                                        //msgBlock        = [4, #6.24(bytes.cborblock)]
+                                        //msgBatchDone    = [5]
+                                        0 => {
+                                            debug!("BlockFetchProtocol received MsgRequestRange");
+                                            self.handle_request(&cbor_array);
+                                            self.state = State::Busy
+                                        }
+                                        1 => {
+                                            debug!("BlockFetchProtocol received MsgClientDone");
+                                            self.state = State::Done;
+                                            self.result = Some(Ok(format!("Sent {} blocks", self.sent_blocks)))
+                                        }
+                                        2 => {
+                                            debug!("BlockFetchProtocol received MsgStartBatch");
+                                            self.state = State::Streaming
+                                        }
+                                        3 => {
+                                            debug!("BlockFetchProtocol received MsgNoBlocks");
+                                            self.state = State::Idle
+                                        }
+                                        4 => {
+                                            debug!("BlockFetchProtocol received MsgBlock");
+                                            self.handle_block(&cbor_array);
+                                        }
+                                        5 => {
+                                            debug!("BlockFetchProtocol received MsgBatchDone");
+                                            self.state = State::Idle;
+                                            self.save_blocks();
+                                        }
+                                        _ => {
+                                            error!("Got unexpected message_id: {}", message_id);
+                                        }
+                                    }
                                 }
                                 _ => {
                                     error!("Unexpected cbor!")
@@ -195,121 +362,235 @@ impl Protocol for BlockFetchProtocol {
     }
 }
 
-trait UnwrapValue {
-    fn integer(&self) -> i128;
-    fn bytes(&self) -> Vec<u8>;
+// Header of a block received with MsgBlock
+pub fn parse_msg_block(cbor_array: Vec<Value>) -> Option<BlockHeader> {
+    match cbor_array.get(1) {
+        Some(Value::Tag(24, wrapped)) => match &**wrapped {
+            Value::Bytes(block) => parse_block_header(block),
+            _ => None,
+        },
+        Some(Value::Bytes(block)) => parse_block_header(block),
+        _ => {
+            warn!("invalid cbor! code: 345");
+            None
+        }
+    }
 }
 
-impl UnwrapValue for Value {
-    fn integer(&self) -> i128 {
-        match self {
-            Value::Integer(integer_value) => { *integer_value }
-            _ => { panic!("not an integer!") }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mux::tcp::Channel;
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    struct Blocks(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl Listener for Blocks {
+        fn handle_block(&mut self, block: &[u8]) {
+            self.0.lock().unwrap().push(block.to_vec());
         }
     }
 
-    fn bytes(&self) -> Vec<u8> {
-        match self {
-            Value::Bytes(bytes_vec) => { bytes_vec.clone() }
-            _ => { panic!("not a byte array!") }
+    struct Store(Vec<(Point, Vec<u8>)>);
+
+    impl BlockBodyStore for Store {
+        fn load_block_bodies(&mut self, first: &Point, last: &Point) -> Option<Vec<Vec<u8>>> {
+            let start = self.0.iter().position(|(point, _)| point == first)?;
+            let end = self.0.iter().position(|(point, _)| point == last)?;
+            Some(self.0[start..=end].iter().map(|(_, block)| block.clone()).collect())
         }
     }
-}
 
-    
-	pub fn parse_msg_block(cbor_array: Vec<Value>) -> Option<BlockHeader> {
-    	let mut msg_block = BlockHeader {
-        	block_number: 0,
-	        slot_number: 0,
-	        hash: vec![],
-    	    prev_hash: vec![],
-        	node_vkey: vec![],
-	        node_vrf_vkey: vec![],
-    	    eta_vrf_0: vec![],
-        	eta_vrf_1: vec![],
-	        leader_vrf_0: vec![],
-    	    leader_vrf_1: vec![],
-        	block_size: 0,
-	        block_body_hash: vec![],
-    	    pool_opcert: vec![],
-        	unknown_0: 0,
-	        unknown_1: 0,
-    	    unknown_2: vec![],
-        	protocol_major_version: 0,
-	        protocol_minor_version: 0,
-	    };
-
-    match &cbor_array[1] {
-        Value::Array(header_array) => {
-            match &header_array[1] {
-                Value::Bytes(wrapped_block_header_bytes) => {
-                    // calculate the block hash
-//                    let hash = Params::new().hash_length(32).to_state().update(&*wrapped_block_header_bytes).finalize();
-//                    parse_msg_block.hash = hash.as_bytes().to_owned();
-
-                    let block_header: Value = de::from_slice(&wrapped_block_header_bytes[..]).unwrap();
-                    match block_header {
-                        Value::Array(block_header_array) => {
-                            match &block_header_array[0] {
-                                Value::Array(block_header_array_inner) => {
-                                    msg_block.block_number = block_header_array_inner[0].integer() as i64;
-                                    msg_block.slot_number = block_header_array_inner[1].integer() as i64;
-                                    msg_block.prev_hash.append(&mut block_header_array_inner[2].bytes());
-                                    msg_block.node_vkey.append(&mut block_header_array_inner[3].bytes());
-                                    msg_block.node_vrf_vkey.append(&mut block_header_array_inner[4].bytes());
-                                    match &block_header_array_inner[5] {
-                                        Value::Array(nonce_array) => {
-                                            msg_block.eta_vrf_0.append(&mut nonce_array[0].bytes());
-                                            msg_block.eta_vrf_1.append(&mut nonce_array[1].bytes());
-                                        }
-                                        _ => {
-                                            warn!("invalid cbor! code: 340");
-                                            return None;
-                                        }
-                                    }
-                                    match &block_header_array_inner[6] {
-                                        Value::Array(leader_array) => {
-                                            msg_block.leader_vrf_0.append(&mut leader_array[0].bytes());
-                                            msg_block.leader_vrf_1.append(&mut leader_array[1].bytes());
-                                        }
-                                        _ => {
-                                            warn!("invalid cbor! code: 341");
-                                            return None;
-                                        }
-                                    }
-                                    msg_block.block_size = block_header_array_inner[7].integer() as i64;
-                                    msg_block.block_body_hash.append(&mut block_header_array_inner[8].bytes());
-                                    msg_block.pool_opcert.append(&mut block_header_array_inner[9].bytes());
-                                    msg_block.unknown_0 = block_header_array_inner[10].integer() as i64;
-                                    msg_block.unknown_1 = block_header_array_inner[11].integer() as i64;
-                                    msg_block.unknown_2.append(&mut block_header_array_inner[12].bytes());
-                                    msg_block.protocol_major_version = block_header_array_inner[13].integer() as i64;
-                                    msg_block.protocol_minor_version = block_header_array_inner[14].integer() as i64;
-                                }
-                                _ => {
-                                    warn!("invalid cbor! code: 342");
-                                    return None;
-                                }
-                            }
-                        }
-                        _ => {
-                            warn!("invalid cbor! code: 343");
-                            return None;
-                        }
-                    }
-                }
-                _ => {
-                    warn!("invalid cbor! code: 344");
-                    return None;
-                }
+    /* Headers saved, blocks are stored by the batch. */
+    struct Headers(Arc<Mutex<Vec<Vec<i64>>>>, bool);
+
+    impl BlockStore for Headers {
+        fn save_block(&mut self, pending_blocks: &mut Vec<BlockHeader>, _network_magic: u32) -> io::Result<()> {
+            if self.1 {
+                return Err(io::Error::new(io::ErrorKind::Other, "read only"));
             }
+            self.0.lock().unwrap().push(pending_blocks.drain(..).map(|header| header.slot_number).collect());
+            Ok(())
         }
-        _ => {
-            warn!("invalid cbor! code: 345");
-            return None;
+
+        fn save_byron_block(&mut self, pending_blocks: &mut Vec<ByronHeader>, _network_magic: u32) -> io::Result<()> {
+            self.0.lock().unwrap().push(pending_blocks.drain(..).map(|header| header.slot_number).collect());
+            Ok(())
+        }
+
+        fn load_blocks(&mut self) -> Option<Vec<(i64, Vec<u8>)>> {
+            None
         }
     }
 
-    Some(msg_block)
-}
+    /* Allegra block of the slot without transactions. */
+    fn block(slot: i64) -> Vec<u8> {
+        let int = |i: i64| Value::Integer(i as i128);
+        let bytes = || Value::Bytes(vec![0; 32]);
+        let header_body = Value::Array(vec![
+            int(slot / 10), int(slot), bytes(), bytes(), bytes(),
+            Value::Array(vec![bytes(), bytes()]), Value::Array(vec![bytes(), bytes()]),
+            int(1024), bytes(), bytes(), int(0), int(0), bytes(), int(3), int(0),
+        ]);
+        let mut block = vec![0x82, 0x03, 0x84];
+        block.extend(ser::to_vec_packed(&Value::Array(vec![header_body, bytes()])).unwrap());
+        block.extend(vec![0x80, 0x80, 0xa0]);
+        block
+    }
 
+    /* Byron main block of the epoch and slot, empty apart from the header. */
+    fn byron_block(epoch: i64, slot: i64) -> Vec<u8> {
+        let int = |i: i64| Value::Integer(i as i128);
+        let header = Value::Array(vec![
+            int(764824073), Value::Bytes(vec![1; 32]), Value::Array(vec![]),
+            Value::Array(vec![
+                Value::Array(vec![int(epoch), int(slot)]), Value::Bytes(vec![]),
+                Value::Array(vec![int(epoch * 21600 + slot)]), Value::Array(vec![]),
+            ]),
+            Value::Array(vec![]),
+        ]);
+        let mut block = vec![0x82, 0x01, 0x83];
+        block.extend(ser::to_vec_packed(&header).unwrap());
+        block.extend(vec![0x80, 0x80]);
+        block
+    }
+
+    fn msg(values: Vec<Value>) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(values)).unwrap()
+    }
+
+    #[test]
+    fn blockfetch_client_works() {
+        let blocks = Arc::new(Mutex::new(Vec::new()));
+        let mut client = BlockFetchProtocol {
+            notify: Some(Box::new(Blocks(blocks.clone()))),
+            ..BlockFetchProtocol::range((1, vec![1; 32]), (2, vec![2; 32]))
+        };
+
+        assert_eq!(client.send_data().unwrap(), msg(vec![
+            Value::Integer(0),
+            Value::Array(vec![Value::Integer(1), Value::Bytes(vec![1; 32])]),
+            Value::Array(vec![Value::Integer(2), Value::Bytes(vec![2; 32])]),
+        ]));
+        assert_eq!(client.agency(), Agency::Server);
+        client.receive_data(msg(vec![Value::Integer(2)]));
+        client.receive_data(msg(vec![Value::Integer(4), Value::Tag(24, Box::new(Value::Bytes(vec![0x80])))]));
+        client.receive_data(msg(vec![Value::Integer(4), Value::Bytes(vec![0x81, 0x00])]));
+        assert_eq!(client.agency(), Agency::Server);
+        client.receive_data(msg(vec![Value::Integer(5)]));
+        assert_eq!(client.agency(), Agency::Client);
+
+        assert_eq!(client.send_data().unwrap(), msg(vec![Value::Integer(1)]));
+        assert_eq!(client.agency(), Agency::None);
+        assert_eq!(client.result(), Ok("Received 2 blocks".to_string()));
+        assert_eq!(*blocks.lock().unwrap(), vec![vec![0x80], vec![0x81, 0x00]]);
+    }
+
+    #[test]
+    fn blockfetch_client_store_works() {
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        let mut client = BlockFetchProtocol {
+            store: Some(Box::new(Headers(saved.clone(), false))),
+            ..BlockFetchProtocol::range((10, vec![1; 32]), (20, vec![2; 32]))
+        };
+        client.send_data().unwrap();
+        client.receive_data(msg(vec![Value::Integer(2)]));
+        client.receive_data(msg(vec![Value::Integer(4), Value::Bytes(block(10))]));
+        client.receive_data(msg(vec![Value::Integer(4), Value::Bytes(block(20))]));
+        assert!(saved.lock().unwrap().is_empty());
+        client.receive_data(msg(vec![Value::Integer(5)]));
+        assert_eq!(*saved.lock().unwrap(), vec![vec![10, 20]]);
+        assert!(client.pending_blocks.is_empty());
+
+        let mut client = BlockFetchProtocol {
+            store: Some(Box::new(Headers(saved.clone(), true))),
+            ..BlockFetchProtocol::range((10, vec![1; 32]), (10, vec![1; 32]))
+        };
+        client.send_data().unwrap();
+        client.receive_data(msg(vec![Value::Integer(2)]));
+        client.receive_data(msg(vec![Value::Integer(4), Value::Bytes(block(10))]));
+        client.receive_data(msg(vec![Value::Integer(5)]));
+        assert_eq!(client.agency(), Agency::None);
+        assert!(client.result().unwrap_err().contains("read only"));
+    }
+
+    #[test]
+    fn blockfetch_client_store_byron_works() {
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        let mut client = BlockFetchProtocol {
+            store: Some(Box::new(Headers(saved.clone(), false))),
+            ..BlockFetchProtocol::range((43300, vec![1; 32]), (4492800, vec![2; 32]))
+        };
+        client.send_data().unwrap();
+        client.receive_data(msg(vec![Value::Integer(2)]));
+        client.receive_data(msg(vec![Value::Integer(4), Value::Bytes(byron_block(2, 100))]));
+        client.receive_data(msg(vec![Value::Integer(4), Value::Bytes(block(4492800))]));
+        client.receive_data(msg(vec![Value::Integer(5)]));
+        assert_eq!(*saved.lock().unwrap(), vec![vec![43300], vec![4492800]]);
+        assert!(client.pending_byron_blocks.is_empty());
+    }
+
+    #[test]
+    fn blockfetch_client_no_blocks_works() {
+        let mut client = BlockFetchProtocol::range((1, vec![1; 32]), (2, vec![2; 32]));
+        client.send_data().unwrap();
+        client.receive_data(msg(vec![Value::Integer(3)]));
+        assert_eq!(client.send_data().unwrap(), msg(vec![Value::Integer(1)]));
+        assert_eq!(client.result(), Ok("Received 0 blocks".to_string()));
+    }
+
+    #[test]
+    fn blockfetch_server_works() {
+        let store = Store((1..=3).map(|slot| ((slot, vec![slot as u8; 32]), vec![0x80 + slot as u8])).collect());
+        let blocks = Arc::new(Mutex::new(Vec::new()));
+        let mut client = BlockFetchProtocol {
+            notify: Some(Box::new(Blocks(blocks.clone()))),
+            ranges: vec![((1, vec![1; 32]), (2, vec![2; 32])), ((2, vec![2; 32]), (4, vec![4; 32]))],
+            ..Default::default()
+        };
+        let mut server = BlockFetchProtocol::serve(Box::new(store));
+        assert_eq!(server.role(), Agency::Server);
+
+        while client.agency() != Agency::None {
+            if client.agency() == client.role() {
+                server.receive_data(client.send_data().unwrap());
+            } else {
+                client.receive_data(server.send_data().unwrap());
+            }
+            assert_eq!(client.agency(), server.agency());
+        }
+        assert_eq!(server.msg_block(vec![0x81]), vec![0x82, 0x04, 0xd8, 0x18, 0x41, 0x81]);
+        assert_eq!(*blocks.lock().unwrap(), vec![vec![0x81], vec![0x82]]);
+        assert_eq!(client.result(), Ok("Received 2 blocks".to_string()));
+        assert_eq!(server.result(), Ok("Sent 2 blocks".to_string()));
+    }
+
+    struct Bodies;
+
+    impl BlockBodyStore for Bodies {
+        fn load_block_bodies(&mut self, first: &Point, last: &Point) -> Option<Vec<Vec<u8>>> {
+            Some((first.0..=last.0).map(|slot| vec![0x80 + slot as u8]).collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn blockfetch_responder_works() {
+        let (client, server) = tokio::io::duplex(1024);
+
+        let cli = async move {
+            let client = Channel::new(client);
+            let result = client.execute(BlockFetchProtocol::range((1, vec![1; 32]), (3, vec![3; 32]))).await;
+            assert_eq!(result, Ok("Received 3 blocks".to_string()));
+        };
+        let srv = async move {
+            let server = Channel::new(server);
+            let result = server.execute(BlockFetchProtocol::serve(Box::new(Bodies))).await;
+            assert_eq!(result, Ok("Sent 3 blocks".to_string()));
+        };
+
+        tokio::join!(cli, srv);
+    }
+}
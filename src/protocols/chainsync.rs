@@ -10,23 +10,27 @@ SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 */
 
 use std::{
+    collections::VecDeque,
     time::{Duration, Instant},
+    future::pending,
     io,
     ops::Sub,
 };
 
 use log::{debug, error, info, trace, warn};
-use serde_cbor::{de, Deserializer, ser, Value};
+use serde_cbor::{Deserializer, ser, Value};
+use tokio::{sync::watch, time::sleep};
 
 use crate::{
     Agency,
     Protocol,
+    Ready,
     BlockStore,
     BlockHeader,
+    Point,
+    block::{parse_byron_header, parse_header, ByronHeader, Era},
 };
 
-use blake2b_simd::Params;
-
 #[derive(Debug)]
 pub enum State {
     Idle,
@@ -40,17 +44,97 @@ pub enum State {
 pub enum Mode {
     Sync,
     SendTip,
+    Serve,
 }
 
-#[derive(Debug)]
+// Where the client starts to sync.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Start {
+    // Recent blocks of the store and the defaults of the network
+    Store,
+    // First of the points found on the chain of the server
+    Points(Vec<Point>),
+    Origin,
+    // Current tip of the server
+    Tip,
+}
+
+// When the client ends the sync with MsgDone.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    // Sync until the server is done
+    Never,
+    // Header at or after the slot received
+    Slot(i64),
+    // Header of the point (or a later slot) received
+    Point(Point),
+    // Number of headers received
+    Blocks(usize),
+    // Header of the tip received
+    Tip,
+}
+
+// Network with the point to start syncing from when nothing is stored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Network {
+    pub name: String,
+    pub magic: u32,
+    /* Usually the last Byron block to skip the Byron era, None for the origin. */
+    pub start_point: Option<Point>,
+}
+
+impl Network {
+    pub fn new(name: &str, magic: u32, start_point: Option<Point>) -> Self {
+        Network { name: name.to_string(), magic, start_point }
+    }
+
+    // Defaults of the well known networks
+    pub fn known() -> Vec<Network> {
+        let point = |slot, hash| Some((slot, hex::decode(hash).unwrap()));
+        vec![
+            Network::new("mainnet", 764824073, point(4492799, "f8084c61b6a238acec985b59310b6ecec49c0ab8352249afd7268da5cff2a457")),
+            Network::new("preprod", 1, None),
+            Network::new("preview", 2, None),
+            /* Retired legacy testnet */
+            Network::new("testnet", 1097911063, point(1598399, "7e16781b40ebf8b6da18f7b5e8ade855d6738095ef2f1c58c77e88b6e45997a4")),
+            Network::new("guild", 141, point(359, "baa280a8c640c186e44e2b78de82930e7524d8c7548c5c674aa280e671ce8a45")),
+        ]
+    }
+
+    pub fn from_magic(magic: u32) -> Option<Network> {
+        Network::known().into_iter().find(|network| network.magic == magic)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Tip {
     pub block_number: i64,
     pub slot_number: i64,
     pub hash: Vec<u8>,
 }
 
+// Events of the chain sync, all of them optional.
 pub trait Listener {
-    fn handle_tip(&mut self, msg_roll_forward: &BlockHeader);
+    // Header of the tip was received
+    fn handle_tip(&mut self, _msg_roll_forward: &BlockHeader) {}
+
+    // Every header received
+    fn handle_roll_forward(&mut self, _msg_roll_forward: &BlockHeader, _tip: &Tip) {}
+
+    // Every Byron header received, these are not stored
+    fn handle_byron_roll_forward(&mut self, _header: &ByronHeader, _tip: &Tip) {}
+
+    // Chain was rolled back to the point, None for the origin
+    fn handle_rollback(&mut self, _point: Option<&Point>) {}
+
+    // Sync starts from the point, None for the origin
+    fn handle_intersect_found(&mut self, _point: Option<&Point>, _tip: &Tip) {}
+
+    // None of the points offered is on the chain of the server
+    fn handle_intersect_not_found(&mut self, _tip: &Tip) {}
+
+    // Periodic progress of the sync, and on reaching the tip, Byron included
+    fn handle_progress(&mut self, _block_number: i64, _slot_number: i64, _tip: &Tip) {}
 }
 
 pub struct ChainSyncProtocol {
@@ -59,12 +143,31 @@ pub struct ChainSyncProtocol {
     pub last_insert_time: Instant,
     pub store: Option<Box<dyn BlockStore>>,
     pub network_magic: u32,
+    /* Start point of an empty store, defaults to the known network of the magic. */
+    pub network: Option<Network>,
     pub pending_blocks: Vec<BlockHeader>,
+    pub pending_byron_blocks: Vec<ByronHeader>,
     pub state: State,
     pub result: Option<Result<String, String>>,
     pub is_intersect_found: bool,
     pub tip_to_intersect: Option<Tip>,
     pub notify: Option<Box<dyn Listener>>,
+    pub start: Start,
+    pub stop: Stop,
+    pub roll_forwards: usize,
+    pub is_stop_reached: bool,
+    /* Number of MsgRequestNext sent without waiting for replies, 1 disables pipelining. */
+    pub pipeline_depth: usize,
+    /* MsgRequestNext sent, or received when serving, and not answered yet. */
+    pub outstanding: usize,
+    /* Server: points received with MsgFindIntersect, None for the origin. */
+    pub requested_points: Vec<Option<Point>>,
+    /* Server: points sent to the client, ending with its read pointer. */
+    pub served_points: VecDeque<Point>,
+    /* Server: the client learns of a new read pointer by rolling back to it first. */
+    pub is_rollback_pending: bool,
+    /* Server: changes of the store, to wake up a client parked at the tip. */
+    pub updates: Option<watch::Receiver<()>>,
 }
 
 impl Default for ChainSyncProtocol {
@@ -75,28 +178,61 @@ impl Default for ChainSyncProtocol {
             last_insert_time: Instant::now(),
             store: None,
             network_magic: 764824073,
+            network: None,
             pending_blocks: Vec::new(),
+            pending_byron_blocks: Vec::new(),
             state: State::Idle,
             result: None,
             is_intersect_found: false,
             tip_to_intersect: None,
             notify: None,
+            start: Start::Store,
+            stop: Stop::Never,
+            roll_forwards: 0,
+            is_stop_reached: false,
+            pipeline_depth: 1,
+            outstanding: 0,
+            requested_points: Vec::new(),
+            served_points: VecDeque::new(),
+            is_rollback_pending: false,
+            updates: None,
         }
     }
 }
 
 impl ChainSyncProtocol {
     const FIVE_SECS: Duration = Duration::from_secs(5);
+    /* Maximum rollback, the security parameter k. */
+    const MAX_ROLLBACK: usize = 2160;
+    /* Polling interval of stores that don't notify about changes. */
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    // Client syncing the network into the store.
+    pub fn sync(network: Network, store: Box<dyn BlockStore>) -> Self {
+        ChainSyncProtocol {
+            network_magic: network.magic,
+            network: Some(network),
+            store: Some(store),
+            ..Default::default()
+        }
+    }
+
+    // Server following the chain in the store.
+    pub fn serve(mut store: Box<dyn BlockStore>) -> Self {
+        ChainSyncProtocol {
+            mode: Mode::Serve,
+            is_rollback_pending: true,
+            updates: store.subscribe(),
+            store: Some(store),
+            ..Default::default()
+        }
+    }
 
     fn save_block(&mut self, msg_roll_forward: &BlockHeader, is_tip: bool) -> io::Result<()> {
         match self.store.as_mut() {
-            Some(store) => {
+            Some(_) => {
                 self.pending_blocks.push((*msg_roll_forward).clone());
-
-                if is_tip || self.last_insert_time.elapsed() > ChainSyncProtocol::FIVE_SECS {
-                    store.save_block(&mut self.pending_blocks, self.network_magic)?;
-                    self.last_insert_time = Instant::now();
-                }
+                self.flush_blocks(is_tip)?;
             }
             None => {}
         }
@@ -104,6 +240,31 @@ impl ChainSyncProtocol {
         Ok(())
     }
 
+    fn save_byron_block(&mut self, header: ByronHeader, is_tip: bool) -> io::Result<()> {
+        if self.store.is_some() {
+            self.pending_byron_blocks.push(header);
+            self.flush_blocks(is_tip)?;
+        }
+        Ok(())
+    }
+
+    fn flush_blocks(&mut self, is_forced: bool) -> io::Result<()> {
+        if !is_forced && self.last_insert_time.elapsed() <= ChainSyncProtocol::FIVE_SECS {
+            return Ok(());
+        }
+        if let Some(store) = self.store.as_mut() {
+            /* Byron headers precede all others. */
+            if !self.pending_byron_blocks.is_empty() {
+                store.save_byron_block(&mut self.pending_byron_blocks, self.network_magic)?;
+            }
+            if !self.pending_blocks.is_empty() {
+                store.save_block(&mut self.pending_blocks, self.network_magic)?;
+            }
+            self.last_insert_time = Instant::now();
+        }
+        Ok(())
+    }
+
     fn notify_tip(&mut self, msg_roll_forward: &BlockHeader) {
         match &mut self.notify {
             Some(listener) => listener.handle_tip(msg_roll_forward),
@@ -111,34 +272,309 @@ impl ChainSyncProtocol {
         }
     }
 
+    fn rollback(&mut self, point: Option<&Point>) -> io::Result<()> {
+        /* Drop headers not yet stored, the store takes care of the rest. */
+        let slot = point.map_or(-1, |(slot, _)| *slot);
+        self.pending_blocks.retain(|block| block.slot_number <= slot);
+        self.pending_byron_blocks.retain(|block| block.slot_number <= slot);
+        if let Some(store) = self.store.as_mut() {
+            store.rollback(point)?;
+        }
+        if let Some(listener) = &mut self.notify {
+            listener.handle_rollback(point);
+        }
+        Ok(())
+    }
+
     fn jump_to_tip(&mut self, tip: Tip) {
         self.tip_to_intersect = Some(tip);
         self.is_intersect_found = false;
     }
 
-    fn msg_find_intersect(&self, chain_blocks: Vec<(i64, Vec<u8>)>) -> Vec<u8> {
-
-        // figure out how to fix this extra clone later
+    fn msg_find_intersect(&self, chain_blocks: Vec<Option<Point>>) -> Vec<u8> {
         let msg: Value = Value::Array(
             vec![
                 Value::Integer(4), // message_id
-                // Value::Array(points),
-                Value::Array(chain_blocks.iter().map(|(slot, hash)| Value::Array(vec![Value::Integer(*slot as i128), Value::Bytes(hash.clone())])).collect())
+                Value::Array(chain_blocks.iter().map(|point| encode_point(point.as_ref())).collect())
             ]
         );
 
         ser::to_vec_packed(&msg).unwrap()
     }
 
+    // Points offered to the server, None while the store is not ready.
+    fn intersect_points(&mut self) -> Option<Vec<Option<Point>>> {
+        let mut chain_blocks: Vec<Option<Point>> = vec![];
+
+        match &self.start {
+            Start::Store => {
+                /* Classic sync: Use blocks from store if available. */
+                if let Some(store) = self.store.as_mut() {
+                    let blocks = (*store).load_blocks()?;
+                    for (i, block) in blocks.iter().enumerate() {
+                        // all powers of 2 including 0th element 0, 2, 4, 8, 16, 32
+                        if (i == 0) || ((i > 1) && (i & (i - 1) == 0)) {
+                            chain_blocks.push(Some(block.clone()));
+                        }
+                    }
+                }
+            }
+            Start::Points(points) => chain_blocks.extend(points.iter().cloned().map(Some)),
+            Start::Origin => chain_blocks.push(None),
+            /* Without points the server replies with its tip. */
+            Start::Tip => {}
+        }
+
+        /* Tip discovery: Use discovered tip to retrieve header. */
+        if let Some(tip) = self.tip_to_intersect.as_ref() {
+            chain_blocks.push(Some((tip.slot_number, tip.hash.clone())));
+        }
+
+        if self.start == Start::Store {
+            let network = self.network.clone().or_else(|| Network::from_magic(self.network_magic));
+            match network {
+                Some(network) => chain_blocks.push(network.start_point),
+                /* Nothing stored on an unknown network, sync it from the origin. */
+                None if chain_blocks.is_empty() => chain_blocks.push(None),
+                None => {}
+            }
+        }
+
+        Some(chain_blocks)
+    }
+
+    // Whether another MsgRequestNext may be pipelined.
+    fn can_request_next(&self) -> bool {
+        self.mode != Mode::Serve
+            && self.is_intersect_found
+            && !self.is_stop_reached
+            && self.outstanding < self.pipeline_depth.max(1)
+    }
+
+    fn request_next(&mut self) -> Vec<u8> {
+        // request the next block from the server.
+        trace!("msg_request_next ({} outstanding)", self.outstanding);
+        self.outstanding += 1;
+        self.state = State::CanAwait;
+        self.msg_request_next()
+    }
+
+    fn reply_received(&mut self) {
+        self.outstanding -= 1;
+        self.state = match self.outstanding {
+            0 => State::Idle,
+            _ => State::CanAwait,
+        };
+    }
+
+    fn fail(&mut self, error: String) {
+        error!("{}", error);
+        self.state = State::Done;
+        self.result = Some(Err(error));
+    }
+
+    fn check_stop(&mut self, slot_number: i64, hash: &[u8], is_tip: bool) -> bool {
+        self.roll_forwards += 1;
+        self.is_stop_reached = match &self.stop {
+            Stop::Never => false,
+            Stop::Slot(slot) => slot_number >= *slot,
+            Stop::Point((slot, stop_hash)) => slot_number > *slot || (slot_number == *slot && hash == &stop_hash[..]),
+            Stop::Blocks(blocks) => self.roll_forwards >= *blocks,
+            Stop::Tip => is_tip,
+        };
+        self.is_stop_reached
+    }
+
+    // Without the header of the tip when the client already is at the tip.
+    fn check_stop_at_tip(&mut self, point: Option<&Point>, tip: &Tip) {
+        let is_tip = match point {
+            Some((slot, hash)) => *slot == tip.slot_number && *hash == tip.hash,
+            None => tip.hash.is_empty(),
+        };
+        if self.stop == Stop::Tip && is_tip {
+            debug!("tip reached at slot {}", tip.slot_number);
+            self.is_stop_reached = true;
+        }
+    }
+
+    fn byron_roll_forward(&mut self, header: ByronHeader, tip: Tip) {
+        let is_tip = header.slot_number == tip.slot_number && header.hash == tip.hash;
+        trace!("byron block {} of {}", header.block_number, tip.block_number);
+        if is_tip || self.last_log_time.elapsed() > ChainSyncProtocol::FIVE_SECS {
+            if self.mode == Mode::Sync {
+                info!("byron block {} of {}, {:.2}% synced", header.block_number, tip.block_number, (header.block_number as f64 / tip.block_number as f64) * 100.0);
+            }
+            if let Some(listener) = &mut self.notify {
+                listener.handle_progress(header.block_number, header.slot_number, &tip);
+            }
+            self.last_log_time = Instant::now()
+        }
+        if let Some(listener) = &mut self.notify {
+            listener.handle_byron_roll_forward(&header, &tip);
+        }
+        let is_stop = self.check_stop(header.slot_number, &header.hash, is_tip);
+        if let Err(error) = self.save_byron_block(header, is_tip || is_stop) {
+            self.fail(format!("saving blocks failed: {}", error));
+            return;
+        }
+        if !is_tip && self.mode == Mode::SendTip {
+            /* Next time get tip header. */
+            self.jump_to_tip(tip);
+        }
+    }
+
+    // Whether the intersection is preliminary and the client has to go on to the tip.
+    fn intersect_tip(&mut self, tip: &Tip) -> bool {
+        if self.start != Start::Tip || self.tip_to_intersect.is_some() || tip.hash.is_empty() {
+            return false;
+        }
+        self.jump_to_tip(tip.clone());
+        true
+    }
+
     fn msg_request_next(&self) -> Vec<u8> {
         // we just send an array containing the message_id for this one.
         ser::to_vec_packed(&Value::Array(vec![Value::Integer(0)])).unwrap()
     }
+
+    fn msg_done(&self) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(7)])).unwrap()
+    }
+
+    fn msg_await_reply(&self) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(1)])).unwrap()
+    }
+
+    fn msg_roll_forward(&self, header: Vec<u8>, tip: Option<&Tip>) -> Vec<u8> {
+        /* Header is passed on as stored to retain its CBOR tag. */
+        let mut payload = vec![0x83, 0x02];
+        payload.extend(header);
+        payload.append(&mut ser::to_vec_packed(&encode_tip(tip)).unwrap());
+        payload
+    }
+
+    fn msg_roll_backward(&self, point: Option<&Point>, tip: Option<&Tip>) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(3), encode_point(point), encode_tip(tip)])).unwrap()
+    }
+
+    fn msg_intersect_found(&self, point: Option<&Point>, tip: Option<&Tip>) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(5), encode_point(point), encode_tip(tip)])).unwrap()
+    }
+
+    fn msg_intersect_not_found(&self, tip: Option<&Tip>) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(6), encode_tip(tip)])).unwrap()
+    }
+
+    fn load_tip(&mut self) -> Option<Tip> {
+        self.store.as_mut()?.load_tip()
+    }
+
+    fn is_on_chain(&mut self, point: Option<&Point>) -> bool {
+        match (point, self.store.as_mut()) {
+            (None, _) => true,
+            (Some(point), Some(store)) => store.has_point(point),
+            (Some(_), None) => false,
+        }
+    }
+
+    fn serve_intersect(&mut self) -> Vec<u8> {
+        let tip = self.load_tip();
+        let points = std::mem::take(&mut self.requested_points);
+        self.state = State::Idle;
+        for point in points {
+            if self.is_on_chain(point.as_ref()) {
+                debug!("ChainSyncProtocol intersect found at {:?}", point);
+                self.served_points = point.iter().cloned().collect();
+                self.is_rollback_pending = true;
+                return self.msg_intersect_found(point.as_ref(), tip.as_ref());
+            }
+        }
+        debug!("ChainSyncProtocol intersect not found");
+        self.msg_intersect_not_found(tip.as_ref())
+    }
+
+    fn serve_request_next(&mut self) {
+        self.outstanding += 1;
+        /* Requests pipelined behind a parked one wait for new blocks as well. */
+        if let State::Idle = self.state {
+            self.state = State::CanAwait;
+        }
+    }
+
+    fn serve_reply(&mut self, reply: Vec<u8>) -> Vec<u8> {
+        self.outstanding -= 1;
+        self.state = match (self.outstanding, &self.state) {
+            (0, _) => State::Idle,
+            /* MsgAwaitReply was sent for the oldest request only, the others are answered as blocks arrive. */
+            (_, State::MustReply) => State::MustReply,
+            _ => State::CanAwait,
+        };
+        reply
+    }
+
+    fn serve_next(&mut self) -> Option<Vec<u8>> {
+        /* Changes from here on wake up the client, if it has to be parked. */
+        if let Some(updates) = self.updates.as_mut() {
+            updates.borrow_and_update();
+        }
+        let tip = self.load_tip();
+
+        /* Roll back to the newest point sent that is still on the chain. */
+        let read_pointer = self.served_points.back().cloned();
+        if !self.is_on_chain(read_pointer.as_ref()) {
+            while let Some(point) = self.served_points.pop_back() {
+                if self.is_on_chain(Some(&point)) {
+                    self.served_points.push_back(point);
+                    break;
+                }
+            }
+            self.is_rollback_pending = true;
+        }
+        if self.is_rollback_pending {
+            self.is_rollback_pending = false;
+            let point = self.served_points.back().cloned();
+            debug!("ChainSyncProtocol rolling back to {:?}", point);
+            let reply = self.msg_roll_backward(point.as_ref(), tip.as_ref());
+            return Some(self.serve_reply(reply));
+        }
+
+        match self.store.as_mut().and_then(|store| store.load_next_header(read_pointer.as_ref())) {
+            Some((point, header)) => {
+                trace!("ChainSyncProtocol rolling forward to {:?}", point);
+                self.served_points.push_back(point);
+                if self.served_points.len() > ChainSyncProtocol::MAX_ROLLBACK {
+                    self.served_points.pop_front();
+                }
+                let reply = self.msg_roll_forward(header, tip.as_ref());
+                Some(self.serve_reply(reply))
+            }
+            None => match self.state {
+                State::CanAwait => {
+                    self.state = State::MustReply;
+                    Some(self.msg_await_reply())
+                }
+                /* Client is parked until a new header is stored. */
+                _ => None,
+            },
+        }
+    }
+
+    fn serve_data(&mut self) -> Option<Vec<u8>> {
+        match self.state {
+            State::Intersect => Some(self.serve_intersect()),
+            State::CanAwait | State::MustReply => self.serve_next(),
+            _ => None,
+        }
+    }
 }
 
 impl Protocol for ChainSyncProtocol {
     fn protocol_id(&self) -> u16 {
-        return 0x0002u16;
+        let idx: u16 = 0x0002;
+        match self.role() {
+            Agency::Server => idx ^ 0x8000,
+            _ => idx,
+        }
     }
 
     fn result(&self) -> Result<String, String> {
@@ -146,13 +582,18 @@ impl Protocol for ChainSyncProtocol {
     }
 
     fn role(&self) -> Agency {
-        Agency::Client
+        match self.mode {
+            Mode::Serve => Agency::Server,
+            _ => Agency::Client,
+        }
     }
 
     fn agency(&self) -> Agency {
         return match self.state {
             State::Idle => { Agency::Client }
             State::Intersect => { Agency::Server }
+            /* More requests can be pipelined until a reply has to be awaited. */
+            State::CanAwait if self.can_request_next() => { Agency::Client }
             State::CanAwait => { Agency::Server }
             State::MustReply => { Agency::Server }
             State::Done => { Agency::None }
@@ -164,49 +605,26 @@ impl Protocol for ChainSyncProtocol {
     }
 
     fn send_data(&mut self) -> Option<Vec<u8>> {
+        if self.mode == Mode::Serve {
+            return self.serve_data();
+        }
         return match self.state {
             State::Idle => {
                 trace!("ChainSyncProtocol::State::Idle");
                 if !self.is_intersect_found {
-                    let mut chain_blocks: Vec<(i64, Vec<u8>)> = vec![];
-
-                    /* Classic sync: Use blocks from store if available. */
-                    match self.store.as_mut() {
-                        Some(store) => {
-                            let blocks = (*store).load_blocks()?;
-                            for (i, block) in blocks.iter().enumerate() {
-                                // all powers of 2 including 0th element 0, 2, 4, 8, 16, 32
-                                if (i == 0) || ((i > 1) && (i & (i - 1) == 0)) {
-                                    chain_blocks.push(block.clone());
-                                }
-                            }
-                        }
-                        None => {}
-                    }
-
-                    /* Tip discovery: Use discovered tip to retrieve header. */
-                    if self.tip_to_intersect.is_some() {
-                        let tip = self.tip_to_intersect.as_ref().unwrap();
-                        chain_blocks.push((tip.slot_number, tip.hash.clone()));
-                    }
-
-                    // Last byron block of mainnet
-                    chain_blocks.push((4492799, hex::decode("f8084c61b6a238acec985b59310b6ecec49c0ab8352249afd7268da5cff2a457").unwrap()));
-                    // Last byron block of testnet
-                    chain_blocks.push((1598399, hex::decode("7e16781b40ebf8b6da18f7b5e8ade855d6738095ef2f1c58c77e88b6e45997a4").unwrap()));
-                    // Last byron block of guild
-                    chain_blocks.push((359, hex::decode("baa280a8c640c186e44e2b78de82930e7524d8c7548c5c674aa280e671ce8a45").unwrap()));
+                    let chain_blocks = self.intersect_points()?;
 
                     trace!("intersect");
                     let payload = self.msg_find_intersect(chain_blocks);
                     self.state = State::Intersect;
                     Some(payload)
+                } else if self.is_stop_reached {
+                    debug!("stop reached after {} blocks", self.roll_forwards);
+                    self.state = State::Done;
+                    self.result = Some(Ok(format!("Stopped after {} blocks", self.roll_forwards)));
+                    Some(self.msg_done())
                 } else {
-                    // request the next block from the server.
-                    trace!("msg_request_next");
-                    let payload = self.msg_request_next();
-                    self.state = State::CanAwait;
-                    Some(payload)
+                    Some(self.request_next())
                 }
             }
             State::Intersect => {
@@ -215,7 +633,11 @@ impl Protocol for ChainSyncProtocol {
             }
             State::CanAwait => {
                 debug!("ChainSyncProtocol::State::CanAwait");
-                None
+                if self.can_request_next() {
+                    Some(self.request_next())
+                } else {
+                    None
+                }
             }
             State::MustReply => {
                 debug!("ChainSyncProtocol::State::MustReply");
@@ -228,6 +650,23 @@ impl Protocol for ChainSyncProtocol {
         };
     }
 
+    fn ready(&mut self) -> Option<Ready> {
+        match (&self.mode, &self.state) {
+            (Mode::Serve, State::MustReply) => Some(match self.updates.clone() {
+                Some(mut updates) => Box::pin(async move {
+                    if updates.changed().await.is_err() {
+                        /* The store is gone, the chain won't change anymore. */
+                        pending::<()>().await
+                    }
+                }),
+                None => Box::pin(sleep(ChainSyncProtocol::POLL_INTERVAL)),
+            }),
+            /* Loading the intersect points from the store failed, try again later. */
+            (_, State::Idle) => Some(Box::pin(sleep(ChainSyncProtocol::FIVE_SECS))),
+            _ => None,
+        }
+    }
+
     fn receive_data(&mut self, data: Vec<u8>) {
         //msgRequestNext         = [0]
         //msgAwaitReply          = [1]
@@ -248,14 +687,38 @@ impl Protocol for ChainSyncProtocol {
                             match cbor_array[0] {
                                 Value::Integer(message_id) => {
                                     match message_id {
+                                        0 => {
+                                            trace!("MsgRequestNext");
+                                            self.serve_request_next();
+                                        }
                                         1 => {
                                             // Server wants us to wait a bit until it gets a new block
                                             self.state = State::MustReply;
+                                            if self.stop == Stop::Tip && !self.is_stop_reached {
+                                                /* Nothing left to sync, done once the pending replies are in. */
+                                                debug!("tip reached");
+                                                self.is_stop_reached = true;
+                                            }
+                                        }
+                                        2 | 3 if self.outstanding == 0 => {
+                                            self.fail(format!("Reply {} without MsgRequestNext", message_id));
+                                        }
+                                        2 if self.is_stop_reached => {
+                                            debug!("MsgRollForward pipelined past the stop, ignoring");
+                                            self.reply_received();
+                                        }
+                                        2 if is_byron_roll_forward(&cbor_array) => {
+                                            self.reply_received();
+                                            match parse_byron_roll_forward(&cbor_array) {
+                                                Some((header, tip)) => self.byron_roll_forward(header, tip),
+                                                None => warn!("invalid byron header: {:?}", cbor_array),
+                                            }
                                         }
                                         2 => {
                                             // MsgRollForward
+                                            self.reply_received();
                                             match parse_msg_roll_forward(cbor_array) {
-                                                None => { warn!("Probably a byron block. skipping...") }
+                                                None => { warn!("invalid header, skipping...") }
                                                 Some((msg_roll_forward, tip)) => {
                                                     let is_tip = msg_roll_forward.slot_number == tip.slot_number && msg_roll_forward.hash == tip.hash;
                                                     trace!("block {} of {}, {:.2}% synced", msg_roll_forward.block_number, tip.block_number, (msg_roll_forward.block_number as f64 / tip.block_number as f64) * 100.0);
@@ -263,14 +726,20 @@ impl Protocol for ChainSyncProtocol {
                                                         if self.mode == Mode::Sync {
                                                             info!("block {} of {}, {:.2}% synced", msg_roll_forward.block_number, tip.block_number, (msg_roll_forward.block_number as f64 / tip.block_number as f64) * 100.0);
                                                         }
+                                                        if let Some(listener) = &mut self.notify {
+                                                            listener.handle_progress(msg_roll_forward.block_number, msg_roll_forward.slot_number, &tip);
+                                                        }
                                                         self.last_log_time = Instant::now()
                                                     }
+                                                    if let Some(listener) = &mut self.notify {
+                                                        listener.handle_roll_forward(&msg_roll_forward, &tip);
+                                                    }
 
-                                                    /* Classic sync: Store header data. */
-                                                    /* TODO: error handling */
-                                                    let _ = self.save_block(&msg_roll_forward, is_tip);
-
-                                                    if is_tip {
+                                                    /* Classic sync: Store header data, all of it when stopping. */
+                                                    let is_stop = self.check_stop(msg_roll_forward.slot_number, &msg_roll_forward.hash, is_tip);
+                                                    if let Err(error) = self.save_block(&msg_roll_forward, is_tip || is_stop) {
+                                                        self.fail(format!("saving blocks failed: {}", error));
+                                                    } else if is_tip {
                                                         /* Got complete tip header. */
                                                         self.notify_tip(&msg_roll_forward);
                                                     } else {
@@ -282,28 +751,66 @@ impl Protocol for ChainSyncProtocol {
                                                     }
                                                 }
                                             }
-
-                                            self.state = State::Idle;
-
-                                            // testing only so we sync only a single block
-                                            // self.state = State::Done;
-                                            // self.result = Some(Ok(String::from("Done")))
+                                        }
+                                        3 if self.is_stop_reached => {
+                                            debug!("MsgRollBackward pipelined past the stop, ignoring");
+                                            self.reply_received();
                                         }
                                         3 => {
                                             // MsgRollBackward
-                                            let slot = parse_msg_roll_backward(cbor_array);
-                                            warn!("rollback to slot: {}", slot);
-                                            self.state = State::Idle;
+                                            match parse_msg_roll_backward(cbor_array) {
+                                                Some((point, tip)) => {
+                                                    warn!("rollback to slot: {}", point.as_ref().map_or(0, |(slot, _)| *slot));
+                                                    self.reply_received();
+                                                    if let Err(error) = self.rollback(point.as_ref()) {
+                                                        self.fail(format!("rollback failed: {}", error));
+                                                    } else {
+                                                        self.check_stop_at_tip(point.as_ref(), &tip);
+                                                    }
+                                                }
+                                                None => {
+                                                    error!("invalid rollback");
+                                                    self.reply_received();
+                                                }
+                                            }
+                                        }
+                                        4 => {
+                                            debug!("MsgFindIntersect: {:?}", cbor_array);
+                                            self.requested_points = parse_msg_find_intersect(cbor_array);
+                                            self.state = State::Intersect;
                                         }
                                         5 => {
                                             debug!("MsgIntersectFound: {:?}", cbor_array);
                                             self.is_intersect_found = true;
+                                            match (cbor_array.get(1).and_then(decode_point), cbor_array.get(2).and_then(decode_tip)) {
+                                                (Some(point), Some(tip)) => if !self.intersect_tip(&tip) {
+                                                    if let Some(listener) = &mut self.notify {
+                                                        listener.handle_intersect_found(point.as_ref(), &tip);
+                                                    }
+                                                    self.check_stop_at_tip(point.as_ref(), &tip);
+                                                },
+                                                _ => error!("invalid cbor"),
+                                            }
                                             self.state = State::Idle;
                                         }
                                         6 => {
                                             warn!("MsgIntersectNotFound: {:?}", cbor_array);
-                                            self.is_intersect_found = true; // should start syncing at first byron block. We will just skip all byron blocks.
                                             self.state = State::Idle;
+                                            match cbor_array.get(1).and_then(decode_tip) {
+                                                Some(tip) => if !self.intersect_tip(&tip) {
+                                                    if let Some(listener) = &mut self.notify {
+                                                        listener.handle_intersect_not_found(&tip);
+                                                    }
+                                                    /* Only the origin or the tip of an empty chain have nothing to intersect with. */
+                                                    if self.start == Start::Origin || (self.start == Start::Tip && self.tip_to_intersect.is_none()) {
+                                                        self.is_intersect_found = true;
+                                                        self.check_stop_at_tip(None, &tip);
+                                                    } else {
+                                                        self.fail(format!("intersection not found, tip at slot {}", tip.slot_number));
+                                                    }
+                                                },
+                                                None => self.fail("invalid MsgIntersectNotFound".to_string()),
+                                            }
                                         }
                                         7 => {
                                             warn!("MsgDone: {:?}", cbor_array);
@@ -331,161 +838,1011 @@ impl Protocol for ChainSyncProtocol {
     }
 }
 
-trait UnwrapValue {
-    fn integer(&self) -> i128;
-    fn bytes(&self) -> Vec<u8>;
+fn encode_point(point: Option<&Point>) -> Value {
+    match point {
+        Some((slot, hash)) => Value::Array(vec![Value::Integer(*slot as i128), Value::Bytes(hash.clone())]),
+        None => Value::Array(vec![]),
+    }
+}
+
+fn encode_tip(tip: Option<&Tip>) -> Value {
+    match tip {
+        Some(tip) => Value::Array(vec![
+            encode_point(Some(&(tip.slot_number, tip.hash.clone()))),
+            Value::Integer(tip.block_number as i128),
+        ]),
+        None => Value::Array(vec![encode_point(None), Value::Integer(0)]),
+    }
 }
 
-impl UnwrapValue for Value {
-    fn integer(&self) -> i128 {
-        match self {
-            Value::Integer(integer_value) => { *integer_value }
-            _ => { panic!("not an integer!") }
-        }
+// Point or None for the origin
+fn decode_point(value: &Value) -> Option<Option<Point>> {
+    match value {
+        Value::Array(point) if point.is_empty() => Some(None),
+        Value::Array(point) => match (point.first(), point.get(1)) {
+            (Some(Value::Integer(slot)), Some(Value::Bytes(hash))) => Some(Some((*slot as i64, hash.clone()))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn decode_tip(value: &Value) -> Option<Tip> {
+    match value {
+        Value::Array(tip) => match (tip.first().and_then(decode_point), tip.get(1)) {
+            (Some(point), Some(Value::Integer(block_number))) => {
+                let (slot_number, hash) = point.unwrap_or((0, vec![]));
+                Some(Tip { block_number: *block_number as i64, slot_number, hash })
+            }
+            _ => None,
+        },
+        _ => None,
     }
+}
 
-    fn bytes(&self) -> Vec<u8> {
-        match self {
-            Value::Bytes(bytes_vec) => { bytes_vec.clone() }
-            _ => { panic!("not a byte array!") }
+pub fn parse_msg_find_intersect(cbor_array: Vec<Value>) -> Vec<Option<Point>> {
+    match cbor_array.get(1) {
+        Some(Value::Array(points)) => points.iter().filter_map(|point| {
+            let decoded = decode_point(point);
+            if decoded.is_none() {
+                warn!("invalid point: {:?}", point);
+            }
+            decoded
+        }).collect(),
+        _ => {
+            error!("invalid cbor");
+            vec![]
         }
     }
 }
 
-pub fn parse_msg_roll_forward(cbor_array: Vec<Value>) -> Option<(BlockHeader, Tip)> {
-    let mut msg_roll_forward = BlockHeader {
-        block_number: 0,
-        slot_number: 0,
-        hash: vec![],
-        prev_hash: vec![],
-        node_vkey: vec![],
-        node_vrf_vkey: vec![],
-        eta_vrf_0: vec![],
-        eta_vrf_1: vec![],
-        leader_vrf_0: vec![],
-        leader_vrf_1: vec![],
-        block_size: 0,
-        block_body_hash: vec![],
-        pool_opcert: vec![],
-        unknown_0: 0,
-        unknown_1: 0,
-        unknown_2: vec![],
-        protocol_major_version: 0,
-        protocol_minor_version: 0,
-    };
-    let mut tip = Tip {
-        block_number: 0,
-        slot_number: 0,
-        hash: vec![],
+// Whether the header of MsgRollForward is tagged with the Byron era
+pub fn is_byron_roll_forward(cbor_array: &[Value]) -> bool {
+    match cbor_array.get(1) {
+        Some(Value::Array(header_array)) => header_array.first() == Some(&Value::Integer(0)),
+        _ => false,
+    }
+}
+
+pub fn parse_byron_roll_forward(cbor_array: &[Value]) -> Option<(ByronHeader, Tip)> {
+    let header = match cbor_array.get(1) {
+        Some(Value::Array(header_array)) => parse_byron_header(header_array.get(1)?)?,
+        _ => return None,
     };
+    Some((header, decode_tip(cbor_array.get(2)?)?))
+}
 
-    match &cbor_array[1] {
-        Value::Array(header_array) => {
-            match &header_array[1] {
-                Value::Bytes(wrapped_block_header_bytes) => {
-                    // calculate the block hash
-                    let hash = Params::new().hash_length(32).to_state().update(&*wrapped_block_header_bytes).finalize();
-                    msg_roll_forward.hash = hash.as_bytes().to_owned();
-
-                    let block_header: Value = de::from_slice(&wrapped_block_header_bytes[..]).unwrap();
-                    match block_header {
-                        Value::Array(block_header_array) => {
-                            match &block_header_array[0] {
-                                Value::Array(block_header_array_inner) => {
-                                    msg_roll_forward.block_number = block_header_array_inner[0].integer() as i64;
-                                    msg_roll_forward.slot_number = block_header_array_inner[1].integer() as i64;
-                                    msg_roll_forward.prev_hash.append(&mut block_header_array_inner[2].bytes());
-                                    msg_roll_forward.node_vkey.append(&mut block_header_array_inner[3].bytes());
-                                    msg_roll_forward.node_vrf_vkey.append(&mut block_header_array_inner[4].bytes());
-                                    match &block_header_array_inner[5] {
-                                        Value::Array(nonce_array) => {
-                                            msg_roll_forward.eta_vrf_0.append(&mut nonce_array[0].bytes());
-                                            msg_roll_forward.eta_vrf_1.append(&mut nonce_array[1].bytes());
-                                        }
-                                        _ => {
-                                            warn!("invalid cbor! code: 340");
-                                            return None;
-                                        }
-                                    }
-                                    match &block_header_array_inner[6] {
-                                        Value::Array(leader_array) => {
-                                            msg_roll_forward.leader_vrf_0.append(&mut leader_array[0].bytes());
-                                            msg_roll_forward.leader_vrf_1.append(&mut leader_array[1].bytes());
-                                        }
-                                        _ => {
-                                            warn!("invalid cbor! code: 341");
-                                            return None;
-                                        }
-                                    }
-                                    msg_roll_forward.block_size = block_header_array_inner[7].integer() as i64;
-                                    msg_roll_forward.block_body_hash.append(&mut block_header_array_inner[8].bytes());
-                                    msg_roll_forward.pool_opcert.append(&mut block_header_array_inner[9].bytes());
-                                    msg_roll_forward.unknown_0 = block_header_array_inner[10].integer() as i64;
-                                    msg_roll_forward.unknown_1 = block_header_array_inner[11].integer() as i64;
-                                    msg_roll_forward.unknown_2.append(&mut block_header_array_inner[12].bytes());
-                                    msg_roll_forward.protocol_major_version = block_header_array_inner[13].integer() as i64;
-                                    msg_roll_forward.protocol_minor_version = block_header_array_inner[14].integer() as i64;
-                                }
-                                _ => {
-                                    warn!("invalid cbor! code: 342");
-                                    return None;
-                                }
-                            }
-                        }
-                        _ => {
-                            warn!("invalid cbor! code: 343");
-                            return None;
-                        }
-                    }
-                }
-                _ => {
-                    warn!("invalid cbor! code: 344");
-                    return None;
-                }
+pub fn parse_msg_roll_forward(cbor_array: Vec<Value>) -> Option<(BlockHeader, Tip)> {
+    let msg_roll_forward = match cbor_array.get(1) {
+        Some(Value::Array(header_array)) => match (header_array.first(), header_array.get(1)) {
+            (Some(Value::Integer(era)), Some(Value::Bytes(wrapped_block_header_bytes))) => {
+                parse_header(Era::from_index(*era)?, wrapped_block_header_bytes)?
             }
-        }
+            _ => {
+                warn!("invalid cbor! code: 344");
+                return None;
+            }
+        },
         _ => {
             warn!("invalid cbor! code: 345");
             return None;
         }
+    };
+
+    match cbor_array.get(2).and_then(decode_tip) {
+        Some(tip) => Some((msg_roll_forward, tip)),
+        None => {
+            warn!("invalid cbor! code: 347");
+            None
+        }
     }
+}
 
-    match &cbor_array[2] {
-        Value::Array(tip_array) => {
-            match &tip_array[0] {
-                Value::Array(tip_info_array) => {
-                    tip.slot_number = tip_info_array[0].integer() as i64;
-                    tip.hash.append(&mut tip_info_array[1].bytes());
-                }
-                _ => {
-                    warn!("invalid cbor! code: 346");
-                    return None;
-                }
+// Point rolled back to (None for the origin) and tip
+pub fn parse_msg_roll_backward(cbor_array: Vec<Value>) -> Option<(Option<Point>, Tip)> {
+    let point = cbor_array.get(1).and_then(decode_point);
+    let tip = cbor_array.get(2).and_then(decode_tip);
+    match (point, tip) {
+        (Some(point), Some(tip)) => Some((point, tip)),
+        _ => {
+            error!("invalid cbor");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mux::tcp::Channel;
+    use blake2b_simd::Params;
+    use serde_cbor::de;
+    use std::sync::{Arc, Mutex};
+
+    /* Chain of (point, block number) with wrapped Shelley headers of the block number. */
+    #[derive(Clone)]
+    struct Chain(Arc<Mutex<Vec<(Point, i64)>>>);
+
+    impl Chain {
+        fn header(block_number: i64) -> Vec<u8> {
+            let header = Value::Bytes(shelley_header(block_number * 10));
+            ser::to_vec_packed(&Value::Array(vec![Value::Integer(1), header])).unwrap()
+        }
+    }
+
+    impl BlockStore for Chain {
+        fn save_block(&mut self, _pending_blocks: &mut Vec<BlockHeader>, _network_magic: u32) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn load_blocks(&mut self) -> Option<Vec<(i64, Vec<u8>)>> {
+            None
+        }
+
+        fn rollback(&mut self, point: Option<&Point>) -> io::Result<()> {
+            let slot = point.map_or(-1, |(slot, _)| *slot);
+            self.0.lock().unwrap().retain(|((s, _), _)| *s <= slot);
+            Ok(())
+        }
+
+        fn load_tip(&mut self) -> Option<Tip> {
+            self.0.lock().unwrap().last().map(|((slot, hash), block_number)| Tip {
+                block_number: *block_number,
+                slot_number: *slot,
+                hash: hash.clone(),
+            })
+        }
+
+        fn has_point(&mut self, point: &Point) -> bool {
+            self.0.lock().unwrap().iter().any(|(p, _)| p == point)
+        }
+
+        fn load_next_header(&mut self, point: Option<&Point>) -> Option<(Point, Vec<u8>)> {
+            let chain = self.0.lock().unwrap();
+            let next = match point {
+                Some(point) => chain.iter().position(|(p, _)| p == point)? + 1,
+                None => 0,
+            };
+            chain.get(next).map(|(point, block_number)| (point.clone(), Chain::header(*block_number)))
+        }
+    }
+
+    /* Chain announcing its changes to subscribers. */
+    struct Watched(Chain, watch::Receiver<()>);
+
+    impl BlockStore for Watched {
+        fn save_block(&mut self, pending_blocks: &mut Vec<BlockHeader>, network_magic: u32) -> io::Result<()> {
+            self.0.save_block(pending_blocks, network_magic)
+        }
+
+        fn load_blocks(&mut self) -> Option<Vec<(i64, Vec<u8>)>> {
+            self.0.load_blocks()
+        }
+
+        fn load_tip(&mut self) -> Option<Tip> {
+            self.0.load_tip()
+        }
+
+        fn has_point(&mut self, point: &Point) -> bool {
+            self.0.has_point(point)
+        }
+
+        fn load_next_header(&mut self, point: Option<&Point>) -> Option<(Point, Vec<u8>)> {
+            self.0.load_next_header(point)
+        }
+
+        fn subscribe(&mut self) -> Option<watch::Receiver<()>> {
+            Some(self.1.clone())
+        }
+    }
+
+    fn point(slot: i64) -> Point {
+        (slot, vec![slot as u8; 32])
+    }
+
+    fn block(slot: i64) -> (Point, i64) {
+        (point(slot), slot / 10)
+    }
+
+    struct Rollbacks(Arc<Mutex<Vec<Option<Point>>>>);
+
+    impl Listener for Rollbacks {
+        fn handle_rollback(&mut self, point: Option<&Point>) {
+            self.0.lock().unwrap().push(point.cloned());
+        }
+    }
+
+    /* Intersection point found (None for the origin) or not found. */
+    type Intersection = (Option<Option<Point>>, Tip);
+
+    struct Intersections(Arc<Mutex<Vec<Intersection>>>);
+
+    impl Listener for Intersections {
+        fn handle_intersect_found(&mut self, point: Option<&Point>, tip: &Tip) {
+            self.0.lock().unwrap().push((Some(point.cloned()), tip.clone()));
+        }
+
+        fn handle_intersect_not_found(&mut self, tip: &Tip) {
+            self.0.lock().unwrap().push((None, tip.clone()));
+        }
+    }
+
+    fn header(slot: i64) -> BlockHeader {
+        BlockHeader {
+            era: Era::Shelley,
+            block_number: slot / 10,
+            slot_number: slot,
+            hash: vec![slot as u8; 32],
+            prev_hash: vec![],
+            node_vkey: vec![],
+            node_vrf_vkey: vec![],
+            eta_vrf_0: None,
+            eta_vrf_1: None,
+            leader_vrf_0: None,
+            leader_vrf_1: None,
+            vrf_result_0: None,
+            vrf_result_1: None,
+            block_size: 0,
+            block_body_hash: vec![],
+            pool_opcert: vec![],
+            unknown_0: 0,
+            unknown_1: 0,
+            unknown_2: vec![],
+            protocol_major_version: 0,
+            protocol_minor_version: 0,
+        }
+    }
+
+    fn msg(data: Vec<u8>) -> Vec<Value> {
+        match de::from_slice(&data).unwrap() {
+            Value::Array(msg) => msg,
+            value => panic!("array expected, got {:?}", value),
+        }
+    }
+
+    fn request(server: &mut ChainSyncProtocol, request: Value) -> Vec<Value> {
+        server.receive_data(ser::to_vec_packed(&request).unwrap());
+        msg(server.send_data().unwrap())
+    }
+
+    fn request_next() -> Value {
+        Value::Array(vec![Value::Integer(0)])
+    }
+
+    fn find_intersect(points: Vec<Option<Point>>) -> Value {
+        Value::Array(vec![
+            Value::Integer(4),
+            Value::Array(points.iter().map(|point| encode_point(point.as_ref())).collect()),
+        ])
+    }
+
+    #[test]
+    fn chainsync_server_intersect_works() {
+        let chain = Chain(Arc::new(Mutex::new(vec![block(10), block(20)])));
+        let mut server = ChainSyncProtocol::serve(Box::new(chain));
+        assert_eq!(server.role(), Agency::Server);
+        assert_eq!(server.agency(), Agency::Client);
+
+        let tip = encode_tip(Some(&Tip { block_number: 2, slot_number: 20, hash: vec![20; 32] }));
+        assert_eq!(request(&mut server, find_intersect(vec![Some(point(30)), Some(point(10)), None])),
+                   vec![Value::Integer(5), encode_point(Some(&point(10))), tip.clone()]);
+        assert_eq!(server.agency(), Agency::Client);
+        assert_eq!(request(&mut server, find_intersect(vec![Some(point(30))])), vec![Value::Integer(6), tip]);
+
+        server.receive_data(ser::to_vec_packed(&Value::Array(vec![Value::Integer(7)])).unwrap());
+        assert_eq!(server.agency(), Agency::None);
+    }
+
+    #[test]
+    fn chainsync_server_follows_chain() {
+        let chain = Chain(Arc::new(Mutex::new(vec![block(10)])));
+        let mut server = ChainSyncProtocol::serve(Box::new(chain.clone()));
+        let tip = encode_tip(Some(&Tip { block_number: 1, slot_number: 10, hash: vec![10; 32] }));
+
+        /* Client is told its read pointer before rolling forward. */
+        assert_eq!(request(&mut server, request_next()), vec![Value::Integer(3), Value::Array(vec![]), tip.clone()]);
+        request(&mut server, find_intersect(vec![Some(point(10))]));
+        assert_eq!(request(&mut server, request_next()),
+                   vec![Value::Integer(3), encode_point(Some(&point(10))), tip]);
+        request(&mut server, find_intersect(vec![None]));
+        assert_eq!(request(&mut server, request_next())[0], Value::Integer(3));
+        let reply = request(&mut server, request_next());
+        assert_eq!(reply[0], Value::Integer(2));
+        assert_eq!(reply[1], msg(Chain::header(1)).into());
+        assert_eq!(server.agency(), Agency::Client);
+
+        /* Nothing new, client has to wait. */
+        assert_eq!(request(&mut server, request_next()), vec![Value::Integer(1)]);
+        assert_eq!(server.agency(), Agency::Server);
+        assert_eq!(server.send_data(), None);
+        chain.0.lock().unwrap().extend(vec![block(20), block(30)]);
+        let reply = msg(server.send_data().unwrap());
+        assert_eq!(reply[1], msg(Chain::header(2)).into());
+        assert_eq!(reply[2], encode_tip(Some(&Tip { block_number: 3, slot_number: 30, hash: vec![30; 32] })));
+        assert_eq!(request(&mut server, request_next())[1], msg(Chain::header(3)).into());
+
+        /* Switch to a fork after slot 10. */
+        {
+            let mut chain = chain.0.lock().unwrap();
+            chain.truncate(1);
+            chain.push(((25, vec![0; 32]), 2));
+        }
+        let tip = encode_tip(Some(&Tip { block_number: 2, slot_number: 25, hash: vec![0; 32] }));
+        assert_eq!(request(&mut server, request_next()), vec![Value::Integer(3), encode_point(Some(&point(10))), tip]);
+        assert_eq!(request(&mut server, request_next())[1], msg(Chain::header(2)).into());
+    }
+
+    #[tokio::test]
+    async fn chainsync_responder_works() {
+        let (client, server) = tokio::io::duplex(1024);
+        let chain = Chain(Arc::new(Mutex::new(vec![block(10), block(20), block(30)])));
+        let rollbacks = Arc::new(Mutex::new(Vec::new()));
+
+        let cli = {
+            let rollbacks = rollbacks.clone();
+            async move {
+                let client = Channel::new(client);
+                let result = client.execute(ChainSyncProtocol {
+                    start: Start::Origin,
+                    stop: Stop::Blocks(2),
+                    notify: Some(Box::new(Rollbacks(rollbacks))),
+                    ..Default::default()
+                }).await;
+                assert_eq!(result, Ok("Stopped after 2 blocks".to_string()));
+            }
+        };
+        let srv = async move {
+            let server = Channel::new(server);
+            assert_eq!(server.execute(ChainSyncProtocol::serve(Box::new(chain))).await, Ok("Done".to_string()));
+        };
+
+        tokio::join!(cli, srv);
+        /* Roll forwards start at the intersection. */
+        assert_eq!(*rollbacks.lock().unwrap(), vec![None]);
+    }
+
+    #[tokio::test]
+    async fn chainsync_responder_wakes_up_works() {
+        let (client, server) = tokio::io::duplex(1024);
+        let chain = Chain(Arc::new(Mutex::new(vec![block(10), block(20)])));
+        let (updates, subscription) = watch::channel(());
+
+        let cli = async move {
+            let client = Channel::new(client);
+            let result = client.execute(ChainSyncProtocol {
+                start: Start::Origin,
+                stop: Stop::Blocks(3),
+                ..Default::default()
+            }).await;
+            assert_eq!(result, Ok("Stopped after 3 blocks".to_string()));
+        };
+        let srv = {
+            let chain = chain.clone();
+            async move {
+                let server = Channel::new(server);
+                let store = Watched(chain, subscription);
+                assert_eq!(server.execute(ChainSyncProtocol::serve(Box::new(store))).await, Ok("Done".to_string()));
+            }
+        };
+        /* The client is parked at the tip until the next block is announced. */
+        let append = async move {
+            sleep(Duration::from_millis(50)).await;
+            chain.0.lock().unwrap().push(block(30));
+            updates.send(()).unwrap();
+        };
+
+        let sync = async { tokio::join!(cli, srv, append) };
+        assert!(tokio::time::timeout(Duration::from_secs(5), sync).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn chainsync_responder_pipelined_wakes_up_works() {
+        let (client, server) = tokio::io::duplex(1024);
+        let chain = Chain(Arc::new(Mutex::new(vec![block(10), block(20)])));
+        let (updates, subscription) = watch::channel(());
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        let cli = {
+            let events = events.clone();
+            async move {
+                let client = Channel::new(client);
+                let result = client.execute(ChainSyncProtocol {
+                    start: Start::Origin,
+                    stop: Stop::Blocks(5),
+                    pipeline_depth: 3,
+                    notify: Some(Box::new(Headers(events))),
+                    ..Default::default()
+                }).await;
+                assert_eq!(result, Ok("Stopped after 5 blocks".to_string()));
+            }
+        };
+        let srv = {
+            let chain = chain.clone();
+            async move {
+                let server = Channel::new(server);
+                let store = Watched(chain, subscription);
+                assert_eq!(server.execute(ChainSyncProtocol::serve(Box::new(store))).await, Ok("Done".to_string()));
+            }
+        };
+        /* Several requests are parked at the tip, each new block answers one of them. */
+        let append = async move {
+            for slot in (30..).step_by(10) {
+                sleep(Duration::from_millis(20)).await;
+                chain.0.lock().unwrap().push(block(slot));
+                updates.send(()).unwrap();
+            }
+        };
+
+        let sync = async {
+            tokio::select! {
+                _ = async { tokio::join!(cli, srv) } => {}
+                _ = append => {}
             }
-            tip.block_number = tip_array[1].integer() as i64;
+        };
+        assert!(tokio::time::timeout(Duration::from_secs(5), sync).await.is_ok());
+        let slots: Vec<i64> = events.lock().unwrap().iter()
+            .filter(|(event, _)| *event == "roll forward")
+            .map(|(_, slot)| *slot)
+            .collect();
+        assert_eq!(slots, vec![10, 20, 30, 40, 50]);
+    }
+
+    #[test]
+    fn chainsync_server_pipelining_works() {
+        let chain = Chain(Arc::new(Mutex::new(vec![block(10)])));
+        let mut server = ChainSyncProtocol::serve(Box::new(chain.clone()));
+        let next = ser::to_vec_packed(&request_next()).unwrap();
+
+        /* Rollback to the origin and the only block, then the client is parked. */
+        for _ in 0..4 {
+            server.receive_data(next.clone());
         }
-        _ => {
-            warn!("invalid cbor! code: 347");
-            return None;
+        assert_eq!(msg(server.send_data().unwrap())[0], Value::Integer(3));
+        assert_eq!(msg(server.send_data().unwrap())[0], Value::Integer(2));
+        assert_eq!(server.send_data(), Some(server.msg_await_reply()));
+        assert_eq!(server.send_data(), None);
+
+        /* Pipelined behind the parked request, no second MsgAwaitReply. */
+        server.receive_data(next);
+        assert_eq!(server.outstanding, 3);
+        assert_eq!(server.send_data(), None);
+        assert_eq!(server.agency(), Agency::Server);
+        for slot in &[20, 30, 40] {
+            chain.0.lock().unwrap().push(block(*slot));
+            assert_eq!(msg(server.send_data().unwrap())[1], msg(Chain::header(*slot / 10)).into());
+            assert_eq!(server.send_data(), None);
         }
+        assert_eq!(server.outstanding, 0);
+        assert_eq!(server.agency(), Agency::Client);
     }
 
-    Some((msg_roll_forward, tip))
-}
+    #[test]
+    fn chainsync_client_rollback_works() {
+        let chain = Chain(Arc::new(Mutex::new(vec![block(10), block(20), block(30)])));
+        let rollbacks = Arc::new(Mutex::new(Vec::new()));
+        let mut client = ChainSyncProtocol {
+            store: Some(Box::new(chain.clone())),
+            notify: Some(Box::new(Rollbacks(rollbacks.clone()))),
+            pending_blocks: vec![header(40), header(50)],
+            is_intersect_found: true,
+            ..Default::default()
+        };
+        let tip = Tip { block_number: 6, slot_number: 60, hash: vec![60; 32] };
+        let rollback = |point: Option<&Point>| ser::to_vec_packed(&Value::Array(vec![
+            Value::Integer(3),
+            encode_point(point),
+            encode_tip(Some(&tip)),
+        ])).unwrap();
+
+        client.send_data().unwrap();
+        client.receive_data(rollback(Some(&point(40))));
+        assert_eq!(client.agency(), Agency::Client);
+        assert_eq!(client.pending_blocks.iter().map(|block| block.slot_number).collect::<Vec<_>>(), vec![40]);
+        assert_eq!(chain.0.lock().unwrap().len(), 3);
+
+        client.send_data().unwrap();
+        client.receive_data(rollback(Some(&point(20))));
+        assert!(client.pending_blocks.is_empty());
+        assert_eq!(*chain.0.lock().unwrap(), vec![block(10), block(20)]);
+
+        client.send_data().unwrap();
+        client.receive_data(rollback(None));
+        assert!(chain.0.lock().unwrap().is_empty());
+        assert_eq!(*rollbacks.lock().unwrap(), vec![Some(point(40)), Some(point(20)), None]);
+    }
+
+    /* Store that can't write anything. */
+    struct Broken;
+
+    impl BlockStore for Broken {
+        fn save_block(&mut self, _pending_blocks: &mut Vec<BlockHeader>, _network_magic: u32) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::Other, "disk full"))
+        }
+
+        fn load_blocks(&mut self) -> Option<Vec<(i64, Vec<u8>)>> {
+            None
+        }
+
+        fn rollback(&mut self, _point: Option<&Point>) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::Other, "disk full"))
+        }
+    }
+
+    /* Store leaving rollbacks to the default. */
+    struct Append;
+
+    impl BlockStore for Append {
+        fn save_block(&mut self, _pending_blocks: &mut Vec<BlockHeader>, _network_magic: u32) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn load_blocks(&mut self) -> Option<Vec<(i64, Vec<u8>)>> {
+            None
+        }
+    }
+
+    fn roll_backward(point: Option<&Point>) -> Vec<u8> {
+        let tip = Tip { block_number: 6, slot_number: 60, hash: vec![60; 32] };
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(3), encode_point(point), encode_tip(Some(&tip))])).unwrap()
+    }
+
+    #[test]
+    fn chainsync_client_rollback_failure_works() {
+        let mut client = ChainSyncProtocol {
+            store: Some(Box::new(Broken)),
+            is_intersect_found: true,
+            ..Default::default()
+        };
+        client.send_data().unwrap();
+        client.receive_data(roll_backward(Some(&point(40))));
+        assert_eq!(client.agency(), Agency::None);
+        assert_eq!(client.result(), Err("rollback failed: disk full".to_string()));
+
+        /* Stores without rollbacks keep syncing. */
+        let mut client = ChainSyncProtocol {
+            store: Some(Box::new(Append)),
+            is_intersect_found: true,
+            ..Default::default()
+        };
+        client.send_data().unwrap();
+        client.receive_data(roll_backward(Some(&point(40))));
+        assert_eq!(client.agency(), Agency::Client);
+    }
+
+    #[test]
+    fn parse_msg_roll_backward_works() {
+        let tip = Tip { block_number: 6, slot_number: 60, hash: vec![60; 32] };
+        let msg = vec![Value::Integer(3), encode_point(Some(&point(20))), encode_tip(Some(&tip))];
+        assert_eq!(parse_msg_roll_backward(msg), Some((Some(point(20)), tip)));
+        assert_eq!(parse_msg_roll_backward(vec![Value::Integer(3), Value::Array(vec![])]), None);
+    }
+
+    #[test]
+    fn chainsync_client_intersect_events_work() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut client = ChainSyncProtocol {
+            notify: Some(Box::new(Intersections(events.clone()))),
+            ..Default::default()
+        };
+        let tip = Tip { block_number: 6, slot_number: 60, hash: vec![60; 32] };
+
+        client.send_data().unwrap();
+        client.receive_data(ser::to_vec_packed(&Value::Array(vec![
+            Value::Integer(5), encode_point(Some(&point(20))), encode_tip(Some(&tip)),
+        ])).unwrap());
+        client.state = State::Intersect;
+        client.receive_data(ser::to_vec_packed(&Value::Array(vec![Value::Integer(6), encode_tip(Some(&tip))])).unwrap());
+        assert_eq!(*events.lock().unwrap(), vec![(Some(Some(point(20))), tip.clone()), (None, tip)]);
+        /* The start point asked for is not on the chain. */
+        assert_eq!(client.result(), Err("intersection not found, tip at slot 60".to_string()));
+    }
+
+    struct Headers(Arc<Mutex<Vec<(&'static str, i64)>>>);
+
+    impl Listener for Headers {
+        fn handle_tip(&mut self, msg_roll_forward: &BlockHeader) {
+            self.0.lock().unwrap().push(("tip", msg_roll_forward.slot_number));
+        }
+
+        fn handle_roll_forward(&mut self, msg_roll_forward: &BlockHeader, _tip: &Tip) {
+            self.0.lock().unwrap().push(("roll forward", msg_roll_forward.slot_number));
+        }
+
+        fn handle_progress(&mut self, _block_number: i64, slot_number: i64, _tip: &Tip) {
+            self.0.lock().unwrap().push(("progress", slot_number));
+        }
+    }
 
-pub fn parse_msg_roll_backward(cbor_array: Vec<Value>) -> i64 {
-    let mut slot: i64 = 0;
-    match &cbor_array[1] {
-        Value::Array(block) => {
-            if block.len() > 0 {
-                match block[0] {
-                    Value::Integer(parsed_slot) => { slot = parsed_slot as i64 }
-                    _ => { error!("invalid cbor"); }
+    /* Shelley style header of the block at the slot. */
+    fn shelley_header(slot: i64) -> Vec<u8> {
+        let int = |i: i64| Value::Integer(i as i128);
+        let bytes = || Value::Bytes(vec![0; 32]);
+        let header_body = Value::Array(vec![
+            int(slot / 10), int(slot), bytes(), bytes(), bytes(),
+            Value::Array(vec![bytes(), bytes()]), Value::Array(vec![bytes(), bytes()]),
+            int(1024), bytes(), bytes(), int(0), int(0), bytes(), int(2), int(0),
+        ]);
+        ser::to_vec_packed(&Value::Array(vec![header_body, bytes()])).unwrap()
+    }
+
+    fn roll_forward(slot: i64, tip: &Tip) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![
+            Value::Integer(2),
+            Value::Array(vec![Value::Integer(1), Value::Bytes(shelley_header(slot))]),
+            encode_tip(Some(tip)),
+        ])).unwrap()
+    }
+
+    #[test]
+    fn chainsync_client_roll_forward_events_work() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut client = ChainSyncProtocol {
+            notify: Some(Box::new(Headers(events.clone()))),
+            last_log_time: Instant::now(),
+            is_intersect_found: true,
+            ..Default::default()
+        };
+        let hash = Params::new().hash_length(32).to_state().update(&shelley_header(20)).finalize();
+        let tip = Tip { block_number: 2, slot_number: 20, hash: hash.as_bytes().to_vec() };
+
+        for slot in &[10, 20] {
+            client.send_data().unwrap();
+            client.receive_data(roll_forward(*slot, &tip));
+        }
+        assert_eq!(*events.lock().unwrap(), vec![
+            ("roll forward", 10),
+            ("progress", 20),
+            ("roll forward", 20),
+            ("tip", 20),
+        ]);
+    }
+
+    #[test]
+    fn chainsync_client_save_failure_works() {
+        let mut client = ChainSyncProtocol {
+            store: Some(Box::new(Broken)),
+            is_intersect_found: true,
+            ..Default::default()
+        };
+        let hash = Params::new().hash_length(32).to_state().update(&shelley_header(20)).finalize();
+        let tip = Tip { block_number: 2, slot_number: 20, hash: hash.as_bytes().to_vec() };
+        client.send_data().unwrap();
+        client.receive_data(roll_forward(20, &tip));
+        assert_eq!(client.agency(), Agency::None);
+        assert_eq!(client.result(), Err("saving blocks failed: disk full".to_string()));
+    }
+
+    fn find_intersect_points(client: &mut ChainSyncProtocol) -> Vec<Option<Point>> {
+        parse_msg_find_intersect(msg(client.send_data().unwrap()))
+    }
+
+    #[test]
+    fn chainsync_client_start_works() {
+        let mut client = ChainSyncProtocol::default();
+        assert_eq!(find_intersect_points(&mut client), vec![Network::from_magic(764824073).unwrap().start_point]);
+        assert!(Network::from_magic(764824073).unwrap().start_point.is_some());
+
+        let mut client = ChainSyncProtocol {
+            network_magic: 1,
+            ..Default::default()
+        };
+        assert_eq!(find_intersect_points(&mut client), vec![None]);
+
+        /* Networks of the caller take precedence, unknown ones start at the origin. */
+        let client = ChainSyncProtocol::sync(Network::new("local", 42, Some(point(30))), Box::new(Append));
+        assert_eq!((client.network_magic, client.network.is_some()), (42, true));
+        let mut client = ChainSyncProtocol {
+            network: Some(Network::new("local", 42, Some(point(30)))),
+            ..Default::default()
+        };
+        assert_eq!(find_intersect_points(&mut client), vec![Some(point(30))]);
+        let mut client = ChainSyncProtocol {
+            network_magic: 42,
+            ..Default::default()
+        };
+        assert_eq!(find_intersect_points(&mut client), vec![None]);
+
+        let mut client = ChainSyncProtocol {
+            start: Start::Points(vec![point(20), point(10)]),
+            ..Default::default()
+        };
+        assert_eq!(find_intersect_points(&mut client), vec![Some(point(20)), Some(point(10))]);
+
+        let mut client = ChainSyncProtocol {
+            start: Start::Origin,
+            ..Default::default()
+        };
+        assert_eq!(find_intersect_points(&mut client), vec![None]);
+    }
+
+    #[test]
+    fn chainsync_client_start_at_tip_works() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut client = ChainSyncProtocol {
+            start: Start::Tip,
+            notify: Some(Box::new(Intersections(events.clone()))),
+            ..Default::default()
+        };
+        let tip = Tip { block_number: 6, slot_number: 60, hash: vec![60; 32] };
+
+        assert_eq!(find_intersect_points(&mut client), vec![]);
+        client.receive_data(ser::to_vec_packed(&Value::Array(vec![Value::Integer(6), encode_tip(Some(&tip))])).unwrap());
+        assert_eq!(find_intersect_points(&mut client), vec![Some(point(60))]);
+        client.receive_data(ser::to_vec_packed(&Value::Array(vec![
+            Value::Integer(5), encode_point(Some(&point(60))), encode_tip(Some(&tip)),
+        ])).unwrap());
+        assert_eq!(*events.lock().unwrap(), vec![(Some(Some(point(60))), tip)]);
+        assert_eq!(msg(client.send_data().unwrap()), vec![Value::Integer(0)]);
+    }
+
+    fn sync_until(stop: Stop, tip: &Tip, slots: &[i64]) -> ChainSyncProtocol {
+        let mut client = ChainSyncProtocol {
+            stop,
+            is_intersect_found: true,
+            ..Default::default()
+        };
+        for slot in slots {
+            assert_eq!(msg(client.send_data().unwrap()), vec![Value::Integer(0)]);
+            client.receive_data(roll_forward(*slot, tip));
+        }
+        client
+    }
+
+    #[test]
+    fn chainsync_client_stop_works() {
+        let hash = Params::new().hash_length(32).to_state().update(&shelley_header(30)).finalize();
+        let hash_20 = Params::new().hash_length(32).to_state().update(&shelley_header(20)).finalize();
+        let tip = Tip { block_number: 3, slot_number: 30, hash: hash.as_bytes().to_vec() };
+        let stops = vec![
+            (Stop::Slot(15), 2),
+            (Stop::Point((20, hash_20.as_bytes().to_vec())), 2),
+            /* Fork, stop after passing the slot. */
+            (Stop::Point((20, vec![0; 32])), 3),
+            (Stop::Blocks(1), 1),
+            (Stop::Tip, 3),
+        ];
+        for (stop, blocks) in stops {
+            let mut client = sync_until(stop, &tip, &[10, 20, 30][..blocks]);
+            assert_eq!(client.send_data().unwrap(), client.msg_done());
+            assert_eq!(client.agency(), Agency::None);
+            assert_eq!(client.result(), Ok(format!("Stopped after {} blocks", blocks)));
+        }
+
+        let mut client = sync_until(Stop::Never, &tip, &[10, 20, 30]);
+        assert_eq!(msg(client.send_data().unwrap()), vec![Value::Integer(0)]);
+    }
+
+    #[tokio::test]
+    async fn chainsync_client_stop_at_tip_works() {
+        let (client, server) = tokio::io::duplex(1024);
+        let chain = Chain(Arc::new(Mutex::new(vec![block(10), block(20), block(30)])));
+
+        let cli = async move {
+            let client = Channel::new(client);
+            let result = client.execute(ChainSyncProtocol {
+                start: Start::Tip,
+                stop: Stop::Tip,
+                ..Default::default()
+            }).await;
+            assert_eq!(result, Ok("Stopped after 0 blocks".to_string()));
+        };
+        /* No new block is minted, the client is at the tip right away. */
+        let srv = async move {
+            let server = Channel::new(server);
+            assert_eq!(server.execute(ChainSyncProtocol::serve(Box::new(chain))).await, Ok("Done".to_string()));
+        };
+
+        let sync = async { tokio::join!(cli, srv) };
+        assert!(tokio::time::timeout(Duration::from_secs(5), sync).await.is_ok());
+    }
+
+    #[test]
+    fn chainsync_client_stop_at_await_reply_works() {
+        let tip = Tip { block_number: 9, slot_number: 90, hash: vec![90; 32] };
+        let mut client = ChainSyncProtocol {
+            pipeline_depth: 2,
+            stop: Stop::Tip,
+            is_intersect_found: true,
+            ..Default::default()
+        };
+
+        client.send_data().unwrap();
+        client.send_data().unwrap();
+        client.receive_data(roll_forward(10, &tip));
+        client.receive_data(ser::to_vec_packed(&Value::Array(vec![Value::Integer(1)])).unwrap());
+        /* At the tip, no more requests and done once the parked one is answered. */
+        assert_eq!(client.agency(), Agency::Server);
+        client.receive_data(roll_forward(20, &tip));
+        assert_eq!(client.send_data().unwrap(), client.msg_done());
+        assert_eq!(client.result(), Ok("Stopped after 1 blocks".to_string()));
+    }
+
+    #[test]
+    fn chainsync_client_pipelining_works() {
+        let tip = Tip { block_number: 9, slot_number: 90, hash: vec![90; 32] };
+        let mut client = ChainSyncProtocol {
+            pipeline_depth: 3,
+            is_intersect_found: true,
+            ..Default::default()
+        };
+
+        for _ in 0..3 {
+            assert_eq!(client.agency(), Agency::Client);
+            assert_eq!(client.send_data().unwrap(), client.msg_request_next());
+        }
+        assert_eq!(client.agency(), Agency::Server);
+        client.receive_data(roll_forward(10, &tip));
+        assert_eq!(client.outstanding, 2);
+        assert_eq!(client.agency(), Agency::Client);
+        client.send_data().unwrap();
+
+        /* Caught up with the server, no more requests until it replies. */
+        client.receive_data(roll_forward(20, &tip));
+        client.receive_data(ser::to_vec_packed(&Value::Array(vec![Value::Integer(1)])).unwrap());
+        assert_eq!(client.agency(), Agency::Server);
+        client.receive_data(roll_forward(30, &tip));
+        assert_eq!(client.agency(), Agency::Client);
+        client.receive_data(roll_forward(40, &tip));
+        assert_eq!(client.outstanding, 0);
+        assert_eq!(client.roll_forwards, 4);
+        assert_eq!(client.state(), "Idle");
+
+        /* Replies need a request. */
+        client.receive_data(roll_forward(50, &tip));
+        assert_eq!(client.roll_forwards, 4);
+        assert_eq!(client.agency(), Agency::None);
+        assert_eq!(client.result(), Err("Reply 2 without MsgRequestNext".to_string()));
+    }
+
+    #[tokio::test]
+    async fn chainsync_client_pipelining_responder_works() {
+        for depth in &[2, 3, 5] {
+            let (client, server) = tokio::io::duplex(1024);
+            let chain = Chain(Arc::new(Mutex::new(vec![block(10), block(20), block(30)])));
+            let events = Arc::new(Mutex::new(Vec::new()));
+
+            let cli = {
+                let events = events.clone();
+                async move {
+                    let client = Channel::new(client);
+                    let result = client.execute(ChainSyncProtocol {
+                        start: Start::Origin,
+                        stop: Stop::Blocks(8),
+                        pipeline_depth: *depth,
+                        notify: Some(Box::new(Headers(events))),
+                        ..Default::default()
+                    }).await;
+                    assert_eq!(result, Ok("Stopped after 8 blocks".to_string()));
                 }
-            }
+            };
+            let srv = {
+                let chain = chain.clone();
+                async move {
+                    let server = Channel::new(server);
+                    assert_eq!(server.execute(ChainSyncProtocol::serve(Box::new(chain))).await, Ok("Done".to_string()));
+                }
+            };
+            /* The client reaches the tip and keeps waiting on MsgAwaitReply for the blocks to come. */
+            let append = async move {
+                for slot in (40..).step_by(10) {
+                    sleep(Duration::from_millis(20)).await;
+                    chain.0.lock().unwrap().push(block(slot));
+                }
+            };
+
+            let sync = async {
+                tokio::select! {
+                    _ = async { tokio::join!(cli, srv) } => {}
+                    _ = append => {}
+                }
+            };
+            assert!(tokio::time::timeout(Duration::from_secs(5), sync).await.is_ok(), "pipeline depth {}", depth);
+            let slots: Vec<i64> = events.lock().unwrap().iter()
+                .filter(|(event, _)| *event == "roll forward")
+                .map(|(_, slot)| *slot)
+                .collect();
+            assert_eq!(slots, (1..=8).map(|block_number| block_number * 10).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn chainsync_client_pipelining_stop_works() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let tip = Tip { block_number: 9, slot_number: 90, hash: vec![90; 32] };
+        let mut client = ChainSyncProtocol {
+            pipeline_depth: 3,
+            stop: Stop::Blocks(1),
+            last_log_time: Instant::now(),
+            is_intersect_found: true,
+            notify: Some(Box::new(Headers(events.clone()))),
+            ..Default::default()
+        };
+
+        while client.agency() == Agency::Client {
+            client.send_data().unwrap();
+        }
+        for slot in &[10, 20, 30] {
+            assert_eq!(client.agency(), Agency::Server);
+            client.receive_data(roll_forward(*slot, &tip));
+        }
+        assert_eq!(client.send_data().unwrap(), client.msg_done());
+        assert_eq!(client.result(), Ok("Stopped after 1 blocks".to_string()));
+        assert_eq!(*events.lock().unwrap(), vec![("roll forward", 10)]);
+    }
+
+    /* Byron headers and the slots of progress reported. */
+    struct ByronHeaders(Arc<Mutex<Vec<ByronHeader>>>, Arc<Mutex<Vec<i64>>>);
+
+    impl Listener for ByronHeaders {
+        fn handle_byron_roll_forward(&mut self, header: &ByronHeader, _tip: &Tip) {
+            self.0.lock().unwrap().push(header.clone());
+        }
+
+        fn handle_progress(&mut self, _block_number: i64, slot_number: i64, _tip: &Tip) {
+            self.1.lock().unwrap().push(slot_number);
+        }
+    }
+
+    /* Store keeping Byron headers only. */
+    struct ByronStore(Arc<Mutex<Vec<ByronHeader>>>);
+
+    impl BlockStore for ByronStore {
+        fn save_block(&mut self, _pending_blocks: &mut Vec<BlockHeader>, _network_magic: u32) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn load_blocks(&mut self) -> Option<Vec<(i64, Vec<u8>)>> {
+            None
+        }
+
+        fn save_byron_block(&mut self, pending_blocks: &mut Vec<ByronHeader>, _network_magic: u32) -> io::Result<()> {
+            self.0.lock().unwrap().append(pending_blocks);
+            Ok(())
         }
-        _ => { error!("invalid cbor"); }
     }
 
-    slot
+    #[test]
+    fn chainsync_client_byron_roll_forward_works() {
+        let headers = Arc::new(Mutex::new(Vec::new()));
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let mut client = ChainSyncProtocol {
+            stop: Stop::Blocks(1),
+            store: Some(Box::new(ByronStore(stored.clone()))),
+            is_intersect_found: true,
+            notify: Some(Box::new(ByronHeaders(headers.clone(), progress.clone()))),
+            ..Default::default()
+        };
+        let header = ser::to_vec_packed(&Value::Array(vec![
+            Value::Integer(764824073),
+            Value::Bytes(vec![1; 32]),
+            Value::Array(vec![]),
+            Value::Array(vec![Value::Integer(1), Value::Array(vec![Value::Integer(21599)])]),
+            Value::Array(vec![]),
+        ])).unwrap();
+        let tip = Tip { block_number: 9, slot_number: 90, hash: vec![90; 32] };
+
+        client.send_data().unwrap();
+        client.receive_data(ser::to_vec_packed(&Value::Array(vec![
+            Value::Integer(2),
+            Value::Array(vec![
+                Value::Integer(0),
+                Value::Array(vec![
+                    Value::Array(vec![Value::Integer(0), Value::Integer(header.len() as i128)]),
+                    Value::Bytes(header),
+                ]),
+            ]),
+            encode_tip(Some(&tip)),
+        ])).unwrap());
+
+        let headers = headers.lock().unwrap();
+        assert_eq!(headers.len(), 1);
+        assert!(headers[0].epoch_boundary);
+        assert_eq!(*progress.lock().unwrap(), vec![headers[0].slot_number]);
+        assert_eq!((headers[0].block_number, headers[0].slot_number), (21599, 21600));
+        /* Stored right away when stopping. */
+        assert_eq!(*stored.lock().unwrap(), *headers);
+        assert!(client.pending_byron_blocks.is_empty());
+        assert_eq!(client.send_data().unwrap(), client.msg_done());
+    }
 }
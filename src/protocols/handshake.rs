@@ -9,22 +9,48 @@ SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 
 */
 
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt,
+};
 
 use log::debug;
 use serde_cbor::{de, ser, Value, Value::*};
 
 use crate::{Agency, Protocol};
 
-const PROTOCOL_VERSION_1: i128 = 0x01;
-const PROTOCOL_VERSION_2: i128 = 0x02;
-const PROTOCOL_VERSION_SHELLEY: i128 = 0x03;
-const PROTOCOL_VERSION_SHELLEY2: i128 = 0x04;
-const PROTOCOL_VERSION_ALLEGRA: i128 = 0x05;
-const PROTOCOL_VERSION_MARY: i128 = 0x06;
-const MIN_PROTOCOL_VERSION: i128 = PROTOCOL_VERSION_MARY;
-
+pub const PROTOCOL_VERSION_1: u16 = 0x01;
+pub const PROTOCOL_VERSION_2: u16 = 0x02;
+pub const PROTOCOL_VERSION_SHELLEY: u16 = 0x03;
+pub const PROTOCOL_VERSION_SHELLEY2: u16 = 0x04;
+pub const PROTOCOL_VERSION_ALLEGRA: u16 = 0x05;
+pub const PROTOCOL_VERSION_MARY: u16 = 0x06;
+pub const PROTOCOL_VERSION_ALONZO: u16 = 0x07;
+pub const PROTOCOL_VERSION_8: u16 = 0x08;
+pub const PROTOCOL_VERSION_BABBAGE: u16 = 0x09;
+pub const PROTOCOL_VERSION_10: u16 = 0x0a;
+pub const PROTOCOL_VERSION_11: u16 = 0x0b; // adds peer sharing and query
+pub const PROTOCOL_VERSION_12: u16 = 0x0c;
+pub const PROTOCOL_VERSION_13: u16 = 0x0d;
+pub const PROTOCOL_VERSION_CONWAY: u16 = 0x0e;
+
+/* Node-to-client versions are distinguished by the highest bit. */
+pub const PROTOCOL_VERSION_N2C_9: u16 = 0x8009;
+pub const PROTOCOL_VERSION_N2C_10: u16 = 0x800a;
+pub const PROTOCOL_VERSION_N2C_11: u16 = 0x800b;
+pub const PROTOCOL_VERSION_N2C_12: u16 = 0x800c;
+pub const PROTOCOL_VERSION_N2C_13: u16 = 0x800d;
+pub const PROTOCOL_VERSION_N2C_14: u16 = 0x800e;
+pub const PROTOCOL_VERSION_N2C_15: u16 = 0x800f;
+pub const PROTOCOL_VERSION_N2C_16: u16 = 0x8010;
+// Bit set in all node-to-client versions
+pub const PROTOCOL_VERSION_N2C_MASK: u16 = 0x8000;
+
+const MSG_PROPOSE_VERSIONS_MSG_ID: i128 = 0;
 const MSG_ACCEPT_VERSION_MSG_ID: i128 = 1;
+const MSG_REFUSE_MSG_ID: i128 = 2;
+const MSG_QUERY_REPLY_MSG_ID: i128 = 3;
 
 #[derive(Debug, PartialEq)]
 pub enum State {
@@ -33,9 +59,161 @@ pub enum State {
     Done,
 }
 
+/// Parameters of a single protocol version.
+///
+/// Which of the fields go on the wire depends on the version, see
+/// `encode_version_data()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionData {
+    pub network_magic: u32,
+    // Node-to-node version 4 and later.
+    pub initiator_only_diffusion_mode: bool,
+    // Node-to-node version 11 and later.
+    pub peer_sharing: u8,
+    // Node-to-node version 11 and later, node-to-client version 15 and later.
+    pub query: bool,
+}
+
+impl VersionData {
+    pub fn new(network_magic: u32) -> Self {
+        VersionData {
+            network_magic,
+            initiator_only_diffusion_mode: false,
+            peer_sharing: 0,
+            query: false,
+        }
+    }
+
+    // Parameters agreed on with the peer: initiator only if either side is,
+    // peer sharing only as far as both sides enable it.
+    pub fn combine(&self, peer: &VersionData) -> Self {
+        VersionData {
+            network_magic: self.network_magic,
+            initiator_only_diffusion_mode: self.initiator_only_diffusion_mode || peer.initiator_only_diffusion_mode,
+            peer_sharing: self.peer_sharing.min(peer.peer_sharing),
+            query: self.query || peer.query,
+        }
+    }
+}
+
+pub type VersionTable = BTreeMap<u16, VersionData>;
+
+/// Outcome of a successful handshake.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcceptedVersion {
+    pub version: u16,
+    pub data: VersionData,
+}
+
+/// Reason sent along with MsgRefuse.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RefuseReason {
+    // None of the proposed versions is supported, carries the versions we support.
+    VersionMismatch(Vec<u16>),
+    // Parameters of the selected version could not be decoded.
+    HandshakeDecodeError(u16, String),
+    // Parameters were understood but not accepted.
+    Refused(u16, String),
+}
+
+impl RefuseReason {
+    fn decode(value: &Value) -> Result<Self, String> {
+        let items = match value {
+            Value::Array(items) => items.as_slice(),
+            _ => return Err(format!("Unexpected refuse reason {:?}", value)),
+        };
+        match items {
+            [Integer(0), Array(versions)] => {
+                let versions = versions.iter().map(|version| match version {
+                    Integer(version) => decode_version(*version),
+                    _ => Err(format!("Unexpected version {:?}", version)),
+                }).collect::<Result<Vec<u16>, String>>()?;
+                Ok(RefuseReason::VersionMismatch(versions))
+            }
+            [Integer(1), Integer(version), Text(message)] => {
+                Ok(RefuseReason::HandshakeDecodeError(decode_version(*version)?, message.clone()))
+            }
+            [Integer(2), Integer(version), Text(message)] => {
+                Ok(RefuseReason::Refused(decode_version(*version)?, message.clone()))
+            }
+            _ => Err(format!("Unexpected refuse reason {:?}", value)),
+        }
+    }
+
+    fn encode(&self) -> Value {
+        match self {
+            RefuseReason::VersionMismatch(versions) => Array(vec![
+                Integer(0),
+                Array(versions.iter().map(|version| Integer(*version as i128)).collect()),
+            ]),
+            RefuseReason::HandshakeDecodeError(version, message) => Array(vec![
+                Integer(1),
+                Integer(*version as i128),
+                Text(message.clone()),
+            ]),
+            RefuseReason::Refused(version, message) => Array(vec![
+                Integer(2),
+                Integer(*version as i128),
+                Text(message.clone()),
+            ]),
+        }
+    }
+}
+
+impl fmt::Display for RefuseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RefuseReason::VersionMismatch(versions) => write!(f, "version mismatch, supported versions: {:?}", versions),
+            RefuseReason::HandshakeDecodeError(version, message) => write!(f, "decode error for version {}: {}", version, message),
+            RefuseReason::Refused(version, message) => write!(f, "refused version {}: {}", version, message),
+        }
+    }
+}
+
+// Successful reply of the server.
+enum Reply {
+    Accepted(AcceptedVersion),
+    Versions(VersionTable),
+}
+
+/// Reasons for a failed handshake.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandshakeError {
+    // The peer replied with MsgRefuse.
+    Refused(RefuseReason),
+    // The reply could not be parsed or is not acceptable.
+    Protocol(String),
+    // The bearer failed before the handshake finished.
+    Connection(String),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HandshakeError::Refused(reason) => write!(f, "Handshake refused: {}", reason),
+            HandshakeError::Protocol(message) => write!(f, "{}", message),
+            HandshakeError::Connection(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<HandshakeError> for String {
+    fn from(error: HandshakeError) -> Self {
+        error.to_string()
+    }
+}
+
 pub struct HandshakeProtocol {
     role: Agency,
     network_magic: u32,
+    versions: VersionTable,
+    accepted: Option<AcceptedVersion>,
+    error: Option<HandshakeError>,
+    refuse_reason: Option<RefuseReason>,
+    query: bool,
+    peer_versions: Option<VersionTable>,
     state: State,
     result: Option<Result<String, String>>,
 }
@@ -45,6 +223,12 @@ impl HandshakeProtocol {
         HandshakeProtocol {
             role: Agency::Client,
             network_magic,
+            versions: node_to_node_versions(network_magic),
+            accepted: None,
+            error: None,
+            refuse_reason: None,
+            query: false,
+            peer_versions: None,
             state: State::Propose,
             result: None,
         }
@@ -54,121 +238,318 @@ impl HandshakeProtocol {
         HandshakeProtocol {
             role: Agency::Server,
             network_magic,
+            versions: node_to_node_versions(network_magic),
+            accepted: None,
+            error: None,
+            refuse_reason: None,
+            query: false,
+            peer_versions: None,
+            state: State::Propose,
+            result: None,
+        }
+    }
+
+    // Client handshake for the local node socket, proposing node-to-client versions.
+    pub fn node_to_client(network_magic: u32) -> Self {
+        HandshakeProtocol {
+            role: Agency::Client,
+            network_magic,
+            versions: node_to_client_versions(network_magic),
+            accepted: None,
+            error: None,
+            refuse_reason: None,
+            query: false,
+            peer_versions: None,
             state: State::Propose,
             result: None,
         }
     }
 
+    // Client handshake only asking for the versions supported by the server.
+    //
+    // Servers understand the query flag since node-to-node version 11 and
+    // node-to-client version 15, older servers just accept a version as usual.
+    pub fn query(network_magic: u32) -> Self {
+        let mut handshake = HandshakeProtocol::new(network_magic);
+        handshake.set_query(true);
+        handshake
+    }
+
+    // Set the query flag on all proposed versions.
+    pub fn set_query(&mut self, query: bool) {
+        self.query = query;
+        for data in self.versions.values_mut() {
+            data.query = query;
+        }
+    }
+
+    // Versions and parameters the server replied with to a query.
+    pub fn peer_versions(&self) -> Option<&VersionTable> {
+        self.peer_versions.as_ref()
+    }
+
+    // Replace the versions proposed (client) or supported (server).
+    pub fn with_versions(mut self, versions: VersionTable) -> Self {
+        self.versions = versions;
+        self
+    }
+
+    // Version and parameters agreed on, available once the handshake is done.
+    pub fn accepted(&self) -> Option<&AcceptedVersion> {
+        self.accepted.as_ref()
+    }
+
+    // Reason of a failed handshake on the client side.
+    pub fn error(&self) -> Option<&HandshakeError> {
+        self.error.as_ref()
+    }
+
+    // Version accepted by the server, available once the handshake is done.
+    //
+    // Node-to-client versions are reported including the 0x8000 bit.
+    pub fn version(&self) -> Option<u16> {
+        self.accepted.as_ref().map(|accepted| accepted.version)
+    }
+
     // Serialize cbor for MsgProposeVersions
     //
     // Create the byte representation of MsgProposeVersions for sending to the server
-    fn msg_propose_versions(&self, network_magic: u32) -> Vec<u8> {
-        let mut payload_map: BTreeMap<Value, Value> = BTreeMap::new();
-        payload_map.insert(Value::Integer(PROTOCOL_VERSION_1), Value::Integer(network_magic as i128));
-        payload_map.insert(Value::Integer(PROTOCOL_VERSION_2), Value::Integer(network_magic as i128));
-        payload_map.insert(Value::Integer(PROTOCOL_VERSION_SHELLEY), Value::Integer(network_magic as i128));
-        payload_map.insert(Value::Integer(PROTOCOL_VERSION_SHELLEY2), Value::Array(vec![Value::Integer(network_magic as i128), Value::Bool(false)]));
-        payload_map.insert(Value::Integer(PROTOCOL_VERSION_ALLEGRA), Value::Array(vec![Value::Integer(network_magic as i128), Value::Bool(false)]));
-        payload_map.insert(Value::Integer(PROTOCOL_VERSION_MARY), Value::Array(vec![Value::Integer(network_magic as i128), Value::Bool(false)]));
-
+    fn msg_propose_versions(&self) -> Vec<u8> {
         let message = Value::Array(vec![
-            Value::Integer(0), // message_id
-            Value::Map(payload_map)
+            Value::Integer(MSG_PROPOSE_VERSIONS_MSG_ID),
+            encode_version_table(&self.versions),
         ]);
 
         ser::to_vec_packed(&message).unwrap()
     }
 
-    // Search through the cbor values until we find a Text value.
-    fn find_error_message(&self, cbor_value: &Value) -> Result<String, ()> {
-        match cbor_value {
-            Value::Text(cbor_text) => {
-                return Ok(cbor_text.to_owned());
-            }
-            Value::Array(cbor_array) => {
-                for value in cbor_array {
-                    let result = self.find_error_message(value);
-                    if result.is_ok() {
-                        return result;
-                    }
+    // Serialize cbor for MsgAcceptVersion or MsgRefuse depending on the negotiation.
+    fn msg_reply(&self) -> Vec<u8> {
+        let message = match (&self.refuse_reason, &self.accepted) {
+            (None, Some(_)) if self.query => Value::Array(vec![
+                Value::Integer(MSG_QUERY_REPLY_MSG_ID),
+                encode_version_table(&self.versions),
+            ]),
+            (None, Some(accepted)) => Value::Array(vec![
+                Value::Integer(MSG_ACCEPT_VERSION_MSG_ID),
+                Value::Integer(accepted.version as i128),
+                encode_version_data(accepted.version, &accepted.data),
+            ]),
+            (Some(reason), _) => Value::Array(vec![
+                Value::Integer(MSG_REFUSE_MSG_ID),
+                reason.encode(),
+            ]),
+            (None, None) => panic!("no negotiation result"),
+        };
+
+        ser::to_vec_packed(&message).unwrap()
+    }
+
+    // Pick the highest version proposed by the client that we support as well.
+    fn negotiate(&mut self, data: &[u8]) -> Result<AcceptedVersion, RefuseReason> {
+        let mismatch = RefuseReason::VersionMismatch(self.versions.keys().cloned().collect());
+        /* Without a version to blame, the one we would have preferred is reported. */
+        let decode_error = || RefuseReason::HandshakeDecodeError(
+            self.versions.keys().next_back().cloned().unwrap_or(0),
+            format!("Unable to decode ProposeVersions {}", hex::encode(data)),
+        );
+        let proposal = match de::from_slice(data) {
+            Ok(Value::Array(proposal)) => proposal,
+            _ => return Err(decode_error()),
+        };
+        let proposed = match (proposal.first(), proposal.get(1)) {
+            (Some(Value::Integer(MSG_PROPOSE_VERSIONS_MSG_ID)), Some(Value::Map(proposed))) => proposed,
+            _ => return Err(decode_error()),
+        };
+
+        let common = proposed.iter()
+            .filter_map(|(version, data)| match version {
+                Value::Integer(version) if *version >= 0 && *version <= u16::MAX as i128 => Some((*version as u16, data)),
+                _ => None,
+            })
+            .filter(|(version, _)| self.versions.contains_key(version))
+            .max_by_key(|(version, _)| *version);
+
+        match common {
+            Some((version, data)) => {
+                let data = decode_version_data(version, data)
+                    .map_err(|message| RefuseReason::HandshakeDecodeError(version, message))?;
+                /* Queries are answered regardless of the network. */
+                self.query = data.query;
+                if !self.query && data.network_magic != self.network_magic {
+                    return Err(RefuseReason::Refused(version, format!("Expected network magic {}, but was {}", self.network_magic, data.network_magic)));
                 }
+                Ok(AcceptedVersion {
+                    version,
+                    data: self.versions[&version].combine(&data),
+                })
             }
-            _ => {}
+            None => Err(mismatch),
         }
-        return Err(());
     }
 
-    fn validate_data(&self, confirm: Value, hex_data: String) -> Result<String, String> {
+    fn validate_data(&self, confirm: Value, hex_data: String) -> Result<Reply, HandshakeError> {
+        let parse_error = || HandshakeError::Protocol(format!("Unable to parse payload error! {}", hex_data));
+
         let confirm_vec = match &confirm {
             Value::Array(confirm_vec) => { Ok(confirm_vec) }
-            _ => { Err(format!("Unable to parse payload error! {}", hex_data)) }
-        }?;
-
-        let msg_type = match confirm_vec.get(0) {
-            Some(msg_type) => { Ok(msg_type) }
-            None => { Err(format!("Unable to parse payload error! {}", hex_data)) }
+            _ => { Err(parse_error()) }
         }?;
 
-        let _msg_type_int = match msg_type {
-            Value::Integer(msg_type_int) => {
-                if *msg_type_int == MSG_ACCEPT_VERSION_MSG_ID {
-                    Ok(msg_type_int)
-                } else {
-                    match self.find_error_message(&confirm) {
-                        Ok(error_message) => {
-                            Err(error_message)
-                        }
-                        Err(_) => { Err(format!("Unable to parse payload error! {}", hex_data)) }
-                    }
-                }
+        match confirm_vec.first() {
+            Some(Value::Integer(MSG_ACCEPT_VERSION_MSG_ID)) => {}
+            Some(Value::Integer(MSG_QUERY_REPLY_MSG_ID)) => {
+                return match confirm_vec.get(1) {
+                    Some(Value::Map(versions)) => Ok(Reply::Versions(decode_version_table(versions)?)),
+                    _ => Err(parse_error()),
+                };
             }
-            _ => { Err(format!("Unable to parse payload error! {}", hex_data)) }
-        }?;
-
-        let accepted_protocol_value = match confirm_vec.get(1) {
-            Some(accepted_protocol_value) => { Ok(accepted_protocol_value) }
-            None => { Err(format!("Unable to parse payload error! {}", hex_data)) }
-        }?;
+            Some(Value::Integer(MSG_REFUSE_MSG_ID)) => {
+                let reason = confirm_vec.get(1).ok_or_else(parse_error)?;
+                return Err(match RefuseReason::decode(reason) {
+                    Ok(reason) => HandshakeError::Refused(reason),
+                    Err(_) => parse_error(),
+                });
+            }
+            _ => { return Err(parse_error()); }
+        }
 
-        let _accepted_protocol = match accepted_protocol_value {
-            Value::Integer(accepted_protocol) => {
-                if *accepted_protocol < MIN_PROTOCOL_VERSION {
-                    Err(format!("Expected protocol version {}, but was {}", MIN_PROTOCOL_VERSION, accepted_protocol))
+        let accepted_protocol = match confirm_vec.get(1) {
+            Some(Value::Integer(accepted_protocol)) => {
+                let accepted_protocol = decode_version(*accepted_protocol).map_err(HandshakeError::Protocol)?;
+                if !self.versions.contains_key(&accepted_protocol) {
+                    Err(HandshakeError::Protocol(format!("Unexpected protocol version {}", accepted_protocol)))
                 } else {
                     Ok(accepted_protocol)
                 }
             }
-            _ => { Err(format!("Unable to parse payload error! {}", hex_data)) }
+            _ => { Err(parse_error()) }
         }?;
 
-        let accepted_vec_value = match confirm_vec.get(2) {
-            Some(accepted_vec_value) => { Ok(accepted_vec_value) }
-            None => { Err(format!("Unable to parse payload error! {}", hex_data)) }
-        }?;
+        let accepted_data_value = confirm_vec.get(2).ok_or_else(parse_error)?;
+        let accepted_data = decode_version_data(accepted_protocol, accepted_data_value)
+            .map_err(HandshakeError::Protocol)?;
+        if accepted_data.network_magic != self.network_magic {
+            return Err(HandshakeError::Protocol(format!("Expected network magic {}, but was {}", self.network_magic, accepted_data.network_magic)));
+        }
 
-        let accepted_vec = match accepted_vec_value {
-            Value::Array(accepted_vec) => { Ok(accepted_vec) }
-            _ => { Err(format!("Unable to parse payload error! {}", hex_data)) }
-        }?;
+        Ok(Reply::Accepted(AcceptedVersion {
+            version: accepted_protocol,
+            data: accepted_data,
+        }))
+    }
+}
 
-        let accepted_magic_value = match accepted_vec.get(0) {
-            Some(accepted_magic_value) => { Ok(accepted_magic_value) }
-            None => { Err(format!("Unable to parse payload error! {}", hex_data)) }
-        }?;
+fn encode_version_table(versions: &VersionTable) -> Value {
+    Value::Map(versions.iter().map(|(version, data)| {
+        (Value::Integer(*version as i128), encode_version_data(*version, data))
+    }).collect())
+}
+
+fn decode_version_table(versions: &BTreeMap<Value, Value>) -> Result<VersionTable, HandshakeError> {
+    versions.iter().map(|(version, data)| match version {
+        Value::Integer(version) if *version >= 0 && *version <= u16::MAX as i128 => {
+            let version = *version as u16;
+            let data = decode_version_data(version, data).map_err(HandshakeError::Protocol)?;
+            Ok((version, data))
+        }
+        _ => Err(HandshakeError::Protocol(format!("Unexpected version {:?}", version))),
+    }).collect()
+}
 
-        let _accepted_magic = match accepted_magic_value {
-            Value::Integer(accepted_magic) => {
-                if *accepted_magic == self.network_magic as i128 {
-                    Ok(accepted_magic)
+// Encode the parameters in the shape expected for the given version.
+fn encode_version_data(version: u16, data: &VersionData) -> Value {
+    let magic = Value::Integer(data.network_magic as i128);
+    if version & PROTOCOL_VERSION_N2C_MASK != 0 {
+        if version >= PROTOCOL_VERSION_N2C_15 {
+            Value::Array(vec![magic, Value::Bool(data.query)])
+        } else {
+            magic
+        }
+    } else if version >= PROTOCOL_VERSION_11 {
+        Value::Array(vec![
+            magic,
+            Value::Bool(data.initiator_only_diffusion_mode),
+            Value::Integer(data.peer_sharing as i128),
+            Value::Bool(data.query),
+        ])
+    } else if version >= PROTOCOL_VERSION_SHELLEY2 {
+        Value::Array(vec![magic, Value::Bool(data.initiator_only_diffusion_mode)])
+    } else {
+        magic
+    }
+}
+
+fn decode_version_data(version: u16, value: &Value) -> Result<VersionData, String> {
+    let is_n2c = version & PROTOCOL_VERSION_N2C_MASK != 0;
+    let data = match value {
+        Value::Integer(magic) => VersionData::new(decode_magic(*magic)?),
+        Value::Array(items) => match items.as_slice() {
+            [Value::Integer(magic), Value::Bool(flag)] => {
+                let mut data = VersionData::new(decode_magic(*magic)?);
+                if is_n2c {
+                    data.query = *flag;
                 } else {
-                    Err(format!("Expected network magic {}, but was {}", self.network_magic, accepted_magic))
+                    data.initiator_only_diffusion_mode = *flag;
                 }
+                data
             }
-            _ => { Err(format!("Unable to parse payload error! {}", hex_data)) }
-        }?;
+            [Value::Integer(magic), Value::Bool(initiator_only), Value::Integer(peer_sharing), Value::Bool(query)] if !is_n2c => {
+                let mut data = VersionData::new(decode_magic(*magic)?);
+                data.initiator_only_diffusion_mode = *initiator_only;
+                data.peer_sharing = u8::try_from(*peer_sharing)
+                    .map_err(|_| format!("Invalid peer sharing {}", peer_sharing))?;
+                data.query = *query;
+                data
+            }
+            _ => return Err(format!("Unexpected parameters for version {}", version)),
+        },
+        _ => return Err(format!("Unexpected parameters for version {}", version)),
+    };
+    Ok(data)
+}
+
+fn decode_version(version: i128) -> Result<u16, String> {
+    u16::try_from(version).map_err(|_| format!("Invalid version {}", version))
+}
 
-        return Ok(hex_data);
+fn decode_magic(magic: i128) -> Result<u32, String> {
+    if magic < 0 || magic > u32::MAX as i128 {
+        return Err(format!("Invalid network magic {}", magic));
     }
+    Ok(magic as u32)
+}
+
+pub fn node_to_node_versions(network_magic: u32) -> VersionTable {
+    [
+        PROTOCOL_VERSION_1,
+        PROTOCOL_VERSION_2,
+        PROTOCOL_VERSION_SHELLEY,
+        PROTOCOL_VERSION_SHELLEY2,
+        PROTOCOL_VERSION_ALLEGRA,
+        PROTOCOL_VERSION_MARY,
+        PROTOCOL_VERSION_ALONZO,
+        PROTOCOL_VERSION_8,
+        PROTOCOL_VERSION_BABBAGE,
+        PROTOCOL_VERSION_10,
+        PROTOCOL_VERSION_11,
+        PROTOCOL_VERSION_12,
+        PROTOCOL_VERSION_13,
+        PROTOCOL_VERSION_CONWAY,
+    ].iter().map(|version| (*version, VersionData::new(network_magic))).collect()
+}
+
+pub fn node_to_client_versions(network_magic: u32) -> VersionTable {
+    [
+        PROTOCOL_VERSION_N2C_9,
+        PROTOCOL_VERSION_N2C_10,
+        PROTOCOL_VERSION_N2C_11,
+        PROTOCOL_VERSION_N2C_12,
+        PROTOCOL_VERSION_N2C_13,
+        PROTOCOL_VERSION_N2C_14,
+        PROTOCOL_VERSION_N2C_15,
+        PROTOCOL_VERSION_N2C_16,
+    ].iter().map(|version| (*version, VersionData::new(network_magic))).collect()
 }
 
 impl Protocol for HandshakeProtocol {
@@ -205,24 +586,19 @@ impl Protocol for HandshakeProtocol {
         debug!("send: {:?}", self.state);
         match self.state {
             State::Propose => {
-                let payload = self.msg_propose_versions(self.network_magic);
+                let payload = self.msg_propose_versions();
                 self.state = State::Confirm;
                 Some(payload)
             }
             State::Confirm => {
-                /* TODO: [stub] implement proper negotiation, we now use fixed protocol version */
-                self.result = Some(Ok("confirmed".to_string()));
+                let payload = self.msg_reply();
+                self.result = Some(match &self.refuse_reason {
+                    None if self.query => Ok("queried".to_string()),
+                    None => Ok("confirmed".to_string()),
+                    Some(reason) => Err(format!("Refused: {}", reason)),
+                });
                 self.state = State::Done;
-                Some(ser::to_vec(
-                    &Array(vec![
-                           Integer(1),
-                           Integer(6),
-                           Array(vec![
-                               Integer(self.network_magic.into()),
-                               Bool(false),
-                           ]),
-                    ])
-                ).unwrap())
+                Some(payload)
             }
             State::Done => panic!("unexpected send"),
         }
@@ -232,12 +608,36 @@ impl Protocol for HandshakeProtocol {
         debug!("recv: {:?}", self.state);
         match self.state {
             State::Propose => {
+                match self.negotiate(&data) {
+                    Ok(accepted) => self.accepted = Some(accepted),
+                    Err(reason) => self.refuse_reason = Some(reason),
+                }
                 self.state = State::Confirm;
             }
             State::Confirm => {
-                let confirm: Value = de::from_slice(&data[..]).unwrap();
-                debug!("Confirm: {:?}", &confirm);
-                self.result = Some(self.validate_data(confirm, hex::encode(data)));
+                let validated = match de::from_slice(&data[..]) {
+                    Ok(confirm) => {
+                        debug!("Confirm: {:?}", &confirm);
+                        self.validate_data(confirm, hex::encode(&data))
+                    }
+                    Err(_) => Err(HandshakeError::Protocol(format!("Unable to parse payload error! {}", hex::encode(&data)))),
+                };
+                self.result = Some(match validated {
+                    Ok(Reply::Accepted(accepted)) => {
+                        let result = Ok(format!("accepted version {}", accepted.version));
+                        self.accepted = Some(accepted);
+                        result
+                    }
+                    Ok(Reply::Versions(versions)) => {
+                        self.peer_versions = Some(versions);
+                        Ok("queried".to_string())
+                    }
+                    Err(error) => {
+                        let result = Err(error.to_string());
+                        self.error = Some(error);
+                        result
+                    }
+                });
                 self.state = State::Done;
             }
             State::Done => panic!("unexpected recv"),
@@ -291,13 +691,219 @@ mod tests {
     #[test]
     fn handshake_client_works() {
         let magic = 0xdddddddd;
-        let mut client = HandshakeProtocol::new(magic);
+        let mut client = HandshakeProtocol::new(magic).with_versions(
+            (1..=6).map(|version| (version, VersionData::new(magic))).collect()
+        );
         assert_eq!(client.state, State::Propose);
         let data = client.send_data().unwrap();
         assert_eq!(client.state, State::Confirm);
         assert_eq!(data, propose(magic));
         client.receive_data(confirm(magic));
         assert_eq!(client.state, State::Done);
+        assert_eq!(client.version(), Some(6));
+        assert!(client.result().is_ok());
+    }
+
+    #[test]
+    fn handshake_client_current_versions_work() {
+        let magic = 0xdddddddd;
+        let mut client = HandshakeProtocol::new(magic);
+        let data = client.send_data().unwrap();
+        match de::from_slice(&data).unwrap() {
+            Array(propose) => match &propose[1] {
+                Map(versions) => {
+                    assert_eq!(versions.len(), 14);
+                    assert_eq!(versions.get(&Integer(3)), Some(&Integer(magic.into())));
+                    assert_eq!(versions.get(&Integer(10)), Some(&Array(vec![Integer(magic.into()), Bool(false)])));
+                    assert_eq!(versions.get(&Integer(14)), Some(&Array(vec![Integer(magic.into()), Bool(false), Integer(0), Bool(false)])));
+                }
+                _ => panic!("versions expected"),
+            },
+            _ => panic!("array expected"),
+        }
+
+        client.receive_data(ser::to_vec(&Array(vec![
+            Integer(1),
+            Integer(14),
+            Array(vec![Integer(magic.into()), Bool(true), Integer(1), Bool(false)]),
+        ])).unwrap());
+        assert_eq!(client.accepted(), Some(&AcceptedVersion {
+            version: PROTOCOL_VERSION_CONWAY,
+            data: VersionData {
+                network_magic: magic,
+                initiator_only_diffusion_mode: true,
+                peer_sharing: 1,
+                query: false,
+            },
+        }));
+        assert_eq!(client.result(), Ok("accepted version 14".to_string()));
+    }
+
+    #[test]
+    fn handshake_client_rejects_unproposed_version() {
+        let magic = 0xdddddddd;
+        let mut client = HandshakeProtocol::new(magic).with_versions(
+            [(PROTOCOL_VERSION_CONWAY, VersionData::new(magic))].iter().cloned().collect()
+        );
+        client.send_data().unwrap();
+        client.receive_data(confirm(magic));
+        assert_eq!(client.accepted(), None);
+        assert_eq!(client.result(), Err("Unexpected protocol version 6".to_string()));
+    }
+
+    #[test]
+    fn handshake_current_versions_work() {
+        let magic = 0xdddddddd;
+        let mut data = VersionData::new(magic);
+        data.peer_sharing = 1;
+        let mut client = HandshakeProtocol::new(magic).with_versions(
+            (PROTOCOL_VERSION_11..=PROTOCOL_VERSION_CONWAY).map(|version| (version, data.clone())).collect()
+        );
+        let mut server = HandshakeProtocol::expect(magic);
+        server.receive_data(client.send_data().unwrap());
+        client.receive_data(server.send_data().unwrap());
+        assert_eq!(server.version(), Some(PROTOCOL_VERSION_CONWAY));
+        assert_eq!(client.accepted(), server.accepted());
+        assert!(client.result().is_ok());
+    }
+
+    #[test]
+    fn handshake_negotiated_data_works() {
+        let magic = 0xdddddddd;
+        let mut data = VersionData::new(magic);
+        data.initiator_only_diffusion_mode = true;
+        data.peer_sharing = 1;
+        let mut client = HandshakeProtocol::new(magic).with_versions(
+            [(PROTOCOL_VERSION_CONWAY, data.clone())].iter().cloned().collect()
+        );
+        data.initiator_only_diffusion_mode = false;
+        let mut server = HandshakeProtocol::expect(magic).with_versions(
+            [(PROTOCOL_VERSION_CONWAY, data)].iter().cloned().collect()
+        );
+        server.receive_data(client.send_data().unwrap());
+        client.receive_data(server.send_data().unwrap());
+        let accepted = server.accepted().unwrap();
+        assert!(accepted.data.initiator_only_diffusion_mode);
+        assert_eq!(accepted.data.peer_sharing, 1);
+        assert_eq!(client.accepted(), server.accepted());
+
+        /* Peer sharing is only on when both sides enable it. */
+        let mut client = HandshakeProtocol::new(magic).with_versions(
+            [(PROTOCOL_VERSION_CONWAY, VersionData::new(magic))].iter().cloned().collect()
+        );
+        let mut server = HandshakeProtocol::expect(magic);
+        server.versions.get_mut(&PROTOCOL_VERSION_CONWAY).unwrap().peer_sharing = 1;
+        server.receive_data(client.send_data().unwrap());
+        client.receive_data(server.send_data().unwrap());
+        assert_eq!(client.accepted().unwrap().data.peer_sharing, 0);
+    }
+
+    #[test]
+    fn handshake_query_works() {
+        let mut client = HandshakeProtocol::query(0xdddddddd);
+        let mut server = HandshakeProtocol::expect(1);
+        server.receive_data(client.send_data().unwrap());
+        client.receive_data(server.send_data().unwrap());
+        assert_eq!(server.result(), Ok("queried".to_string()));
+        assert_eq!(client.result(), Ok("queried".to_string()));
+        assert_eq!(client.accepted(), None);
+        assert_eq!(client.peer_versions(), Some(&node_to_node_versions(1)));
+    }
+
+    #[test]
+    fn handshake_query_legacy_server_works() {
+        let mut client = HandshakeProtocol::query(0xdddddddd);
+        let mut server = HandshakeProtocol::expect(0xdddddddd).with_versions(
+            node_to_node_versions(0xdddddddd).range(..PROTOCOL_VERSION_11).map(|(v, d)| (*v, d.clone())).collect()
+        );
+        server.receive_data(client.send_data().unwrap());
+        client.receive_data(server.send_data().unwrap());
+        assert_eq!(server.result(), Ok("confirmed".to_string()));
+        assert_eq!(client.peer_versions(), None);
+        assert_eq!(client.version(), Some(PROTOCOL_VERSION_10));
+    }
+
+    #[test]
+    fn handshake_client_refused_works() {
+        let magic = 0xdddddddd;
+        let reasons = vec![
+            RefuseReason::VersionMismatch(vec![PROTOCOL_VERSION_13, PROTOCOL_VERSION_CONWAY]),
+            RefuseReason::HandshakeDecodeError(PROTOCOL_VERSION_CONWAY, "bad parameters".to_string()),
+            RefuseReason::Refused(PROTOCOL_VERSION_CONWAY, "go away".to_string()),
+        ];
+        for reason in reasons {
+            let mut client = HandshakeProtocol::new(magic);
+            client.send_data().unwrap();
+            client.receive_data(ser::to_vec(&Array(vec![Integer(2), reason.encode()])).unwrap());
+            assert_eq!(client.state, State::Done);
+            assert_eq!(client.accepted(), None);
+            assert_eq!(client.error(), Some(&HandshakeError::Refused(reason.clone())));
+            assert_eq!(client.result(), Err(format!("Handshake refused: {}", reason)));
+        }
+    }
+
+    #[test]
+    fn handshake_client_invalid_reply_works() {
+        let mut client = HandshakeProtocol::new(0xdddddddd);
+        client.send_data().unwrap();
+        client.receive_data(vec![0x82, 0x02, 0x80]);
+        assert_eq!(client.error(), Some(&HandshakeError::Protocol("Unable to parse payload error! 820280".to_string())));
+    }
+
+    #[test]
+    fn handshake_client_out_of_range_version_works() {
+        let magic = 0xdddddddd;
+        let mut client = HandshakeProtocol::new(magic);
+        client.send_data().unwrap();
+        /* Version 0x10004 must not be taken for version 4. */
+        client.receive_data(ser::to_vec(&Array(vec![
+            Integer(1), Integer(0x10004), Array(vec![Integer(magic.into()), Bool(false)]),
+        ])).unwrap());
+        assert_eq!(client.error(), Some(&HandshakeError::Protocol("Invalid version 65540".to_string())));
+
+        let mut client = HandshakeProtocol::new(magic);
+        client.send_data().unwrap();
+        client.receive_data(ser::to_vec(&Array(vec![
+            Integer(2), Array(vec![Integer(2), Integer(0x10004), Text("go away".to_string())]),
+        ])).unwrap());
+        assert!(matches!(client.error(), Some(HandshakeError::Protocol(_))));
+    }
+
+    #[test]
+    fn handshake_node_to_client_works() {
+        let magic = 0xdddddddd;
+        let mut client = HandshakeProtocol::node_to_client(magic);
+        let data = client.send_data().unwrap();
+        let propose: Value = de::from_slice(&data).unwrap();
+        match propose {
+            Array(propose) => {
+                assert_eq!(propose[0], Integer(0));
+                match &propose[1] {
+                    Map(versions) => {
+                        assert_eq!(versions.get(&Integer(0x8009)), Some(&Integer(magic.into())));
+                        assert_eq!(versions.get(&Integer(0x8010)), Some(&Array(vec![Integer(magic.into()), Bool(false)])));
+                        assert!(versions.keys().all(|version| *version > Integer(0x8000)));
+                    }
+                    _ => panic!("versions expected"),
+                }
+            }
+            _ => panic!("array expected"),
+        }
+
+        client.receive_data(ser::to_vec(&Array(vec![Integer(1), Integer(0x800a), Integer(magic.into())])).unwrap());
+        assert_eq!(client.state, State::Done);
+        assert_eq!(client.version(), Some(0x800a));
+        assert!(client.result().is_ok());
+    }
+
+    #[test]
+    fn handshake_node_to_client_rejects_node_to_node_version() {
+        let magic = 0xdddddddd;
+        let mut client = HandshakeProtocol::node_to_client(magic);
+        client.send_data().unwrap();
+        client.receive_data(confirm(magic));
+        assert_eq!(client.version(), None);
+        assert!(client.result().is_err());
     }
 
     #[test]
@@ -311,4 +917,106 @@ mod tests {
         assert_eq!(server.state, State::Done);
         assert_eq!(data, confirm(magic));
     }
+
+    fn refuse(server: &mut HandshakeProtocol, proposal: Vec<u8>) -> Value {
+        server.receive_data(proposal);
+        let data = server.send_data().unwrap();
+        assert_eq!(server.state, State::Done);
+        assert!(server.result().is_err());
+        de::from_slice(&data).unwrap()
+    }
+
+    #[test]
+    fn handshake_server_picks_highest_common_version() {
+        let magic = 0xdddddddd;
+        let mut server = HandshakeProtocol::expect(magic).with_versions(
+            [3, 4, 5].iter().map(|version| (*version, VersionData::new(magic))).collect()
+        );
+        server.receive_data(propose(magic));
+        let data = server.send_data().unwrap();
+        assert_eq!(server.version(), Some(5));
+        assert!(server.result().is_ok());
+        assert_eq!(de::from_slice::<Value>(&data).unwrap(), Array(vec![
+            Integer(1),
+            Integer(5),
+            Array(vec![Integer(magic.into()), Bool(false)]),
+        ]));
+    }
+
+    #[test]
+    fn handshake_server_refuses_version_mismatch() {
+        let magic = 0xdddddddd;
+        let mut server = HandshakeProtocol::expect(magic).with_versions(node_to_client_versions(magic));
+        assert_eq!(refuse(&mut server, propose(magic)), Array(vec![
+            Integer(2),
+            Array(vec![
+                Integer(0),
+                Array((0x8009..=0x8010).map(Integer).collect()),
+            ]),
+        ]));
+        assert_eq!(server.version(), None);
+    }
+
+    #[test]
+    fn handshake_server_refuses_network_magic() {
+        let mut server = HandshakeProtocol::expect(0xdddddddd);
+        assert_eq!(refuse(&mut server, propose(0xeeeeeeee)), Array(vec![
+            Integer(2),
+            Array(vec![
+                Integer(2),
+                Integer(6),
+                Text("Expected network magic 3722304989, but was 4008636142".to_string()),
+            ]),
+        ]));
+    }
+
+    #[test]
+    fn handshake_server_refuses_undecodable_proposal() {
+        let mut server = HandshakeProtocol::expect(0xdddddddd).with_versions(
+            [3, 4, 5].iter().map(|version| (*version, VersionData::new(0xdddddddd))).collect()
+        );
+        let proposal = ser::to_vec(&Array(vec![Integer(0), Array(vec![Integer(5)])])).unwrap();
+        assert_eq!(refuse(&mut server, proposal), Array(vec![
+            Integer(2),
+            Array(vec![
+                Integer(1),
+                Integer(5),
+                Text("Unable to decode ProposeVersions 82008105".to_string()),
+            ]),
+        ]));
+    }
+
+    #[test]
+    fn handshake_server_refuses_undecodable_parameters() {
+        let mut server = HandshakeProtocol::expect(0xdddddddd);
+        let proposal = ser::to_vec(&Array(vec![
+            Integer(0),
+            Map(vec![(Integer(6), Text("garbage".to_string()))].into_iter().collect()),
+        ])).unwrap();
+        match refuse(&mut server, proposal) {
+            Array(reply) => assert_eq!(reply[1], Array(vec![
+                Integer(1),
+                Integer(6),
+                Text("Unexpected parameters for version 6".to_string()),
+            ])),
+            _ => panic!("array expected"),
+        }
+
+        let mut server = HandshakeProtocol::expect(0xdddddddd);
+        let proposal = ser::to_vec(&Array(vec![
+            Integer(0),
+            Map(vec![(
+                Integer(PROTOCOL_VERSION_11.into()),
+                Array(vec![Integer(0xdddddddd), Bool(false), Integer(256), Bool(false)]),
+            )].into_iter().collect()),
+        ])).unwrap();
+        match refuse(&mut server, proposal) {
+            Array(reply) => assert_eq!(reply[1], Array(vec![
+                Integer(1),
+                Integer(PROTOCOL_VERSION_11.into()),
+                Text("Invalid peer sharing 256".to_string()),
+            ])),
+            _ => panic!("array expected"),
+        }
+    }
 }
@@ -0,0 +1,336 @@
+/**
+© 2020 PERLUR Group
+
+SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
+
+*/
+
+use std::{
+    convert::TryFrom,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, warn};
+use serde_cbor::{de, ser, Value};
+use tokio::time::sleep;
+
+use crate::{
+    Agency,
+    Protocol,
+    Ready,
+};
+
+// Time between two keep-alives sent by the client
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum State {
+    Client,
+    Server,
+    Done,
+}
+
+pub trait Listener {
+    // Response to the keep-alive with the cookie arrived after rtt
+    fn handle_round_trip(&mut self, cookie: u16, rtt: Duration);
+}
+
+pub struct KeepAliveProtocol {
+    role: Agency,
+    state: State,
+    result: Option<Result<String, String>>,
+    interval: Duration,
+    /* Number of keep-alives to send before MsgDone, None to keep going. */
+    count: Option<usize>,
+    cookie: u16,
+    sent_at: Option<Instant>,
+    notify: Option<Box<dyn Listener>>,
+    round_trips: Vec<(u16, Duration)>,
+    answered: usize,
+}
+
+impl KeepAliveProtocol {
+    pub fn new() -> Self {
+        KeepAliveProtocol {
+            role: Agency::Client,
+            state: State::Client,
+            result: None,
+            interval: KEEP_ALIVE_INTERVAL,
+            count: None,
+            cookie: 0,
+            sent_at: None,
+            notify: None,
+            round_trips: Vec::new(),
+            answered: 0,
+        }
+    }
+
+    pub fn expect() -> Self {
+        KeepAliveProtocol {
+            role: Agency::Server,
+            ..KeepAliveProtocol::new()
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    // Finish with MsgDone after count round trips.
+    pub fn with_count(mut self, count: usize) -> Self {
+        self.count = Some(count);
+        self
+    }
+
+    pub fn with_listener(mut self, notify: Box<dyn Listener>) -> Self {
+        self.notify = Some(notify);
+        self
+    }
+
+    // Cookie and round-trip time of each answered keep-alive.
+    pub fn round_trips(&self) -> &[(u16, Duration)] {
+        &self.round_trips
+    }
+
+    fn msg_keep_alive(&self) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(0), Value::Integer(self.cookie as i128)])).unwrap()
+    }
+
+    fn msg_keep_alive_response(&self) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(1), Value::Integer(self.cookie as i128)])).unwrap()
+    }
+
+    fn msg_done(&self) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(2)])).unwrap()
+    }
+
+    fn fail(&mut self, error: String) {
+        error!("{}", error);
+        self.state = State::Done;
+        self.result = Some(Err(error));
+    }
+
+    fn keep_alive(&mut self) -> Option<Vec<u8>> {
+        if matches!(self.count, Some(count) if self.round_trips.len() >= count) {
+            debug!("KeepAliveProtocol sending MsgDone");
+            self.state = State::Done;
+            self.result = Some(Ok(format!("Received {} responses", self.round_trips.len())));
+            return Some(self.msg_done());
+        }
+        if let Some(sent_at) = self.sent_at {
+            if sent_at.elapsed() < self.interval {
+                return None;
+            }
+            self.cookie = self.cookie.wrapping_add(1);
+        }
+        debug!("KeepAliveProtocol sending MsgKeepAlive {}", self.cookie);
+        self.sent_at = Some(Instant::now());
+        self.state = State::Server;
+        Some(self.msg_keep_alive())
+    }
+
+    fn keep_alive_response(&mut self, cookie: u16) {
+        if cookie != self.cookie {
+            self.fail(format!("KeepAliveProtocol cookie mismatch: sent {}, received {}", self.cookie, cookie));
+            return;
+        }
+        let rtt = self.sent_at.map(|sent_at| sent_at.elapsed()).unwrap_or_default();
+        debug!("KeepAliveProtocol round trip {} took {:?}", cookie, rtt);
+        self.round_trips.push((cookie, rtt));
+        if let Some(notify) = self.notify.as_mut() {
+            notify.handle_round_trip(cookie, rtt);
+        }
+        self.state = State::Client;
+    }
+}
+
+impl Default for KeepAliveProtocol {
+    fn default() -> Self {
+        KeepAliveProtocol::new()
+    }
+}
+
+impl Protocol for KeepAliveProtocol {
+    fn protocol_id(&self) -> u16 {
+        let idx: u16 = 0x0008;
+        match self.role {
+            Agency::Server => idx ^ 0x8000,
+            _ => idx,
+        }
+    }
+
+    fn result(&self) -> Result<String, String> {
+        self.result.clone().unwrap()
+    }
+
+    fn role(&self) -> Agency {
+        self.role
+    }
+
+    fn agency(&self) -> Agency {
+        match self.state {
+            State::Client => Agency::Client,
+            State::Server => Agency::Server,
+            State::Done => Agency::None,
+        }
+    }
+
+    fn state(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
+    fn send_data(&mut self) -> Option<Vec<u8>> {
+        match (&self.state, self.role) {
+            (State::Client, Agency::Client) => self.keep_alive(),
+            (State::Server, Agency::Server) => {
+                debug!("KeepAliveProtocol sending MsgKeepAliveResponse {}", self.cookie);
+                self.answered += 1;
+                self.state = State::Client;
+                Some(self.msg_keep_alive_response())
+            }
+            _ => {
+                warn!("KeepAliveProtocol::State::{:?}", self.state);
+                None
+            }
+        }
+    }
+
+    fn ready(&mut self) -> Option<Ready> {
+        match (&self.state, self.role, self.sent_at) {
+            /* Next keep-alive is due after the interval. */
+            (State::Client, Agency::Client, Some(sent_at)) => {
+                Some(Box::pin(sleep(self.interval.saturating_sub(sent_at.elapsed()))))
+            }
+            _ => None,
+        }
+    }
+
+    fn receive_data(&mut self, data: Vec<u8>) {
+        let cbor_value: Value = match de::from_slice(&data[..]) {
+            Ok(cbor_value) => cbor_value,
+            Err(err) => {
+                self.fail(format!("cbor decode error!: {}, hex: {}", err, hex::encode(&data)));
+                return;
+            }
+        };
+        //msgKeepAlive         = [0, word16]
+        //msgKeepAliveResponse = [1, word16]
+        //msgDone              = [2]
+        match cbor_value {
+            Value::Array(cbor_array) => match (cbor_array.first(), cbor_array.get(1)) {
+                (Some(Value::Integer(0)), Some(Value::Integer(cookie))) if self.role == Agency::Server => {
+                    debug!("KeepAliveProtocol received MsgKeepAlive {}", cookie);
+                    match u16::try_from(*cookie) {
+                        Ok(cookie) => {
+                            self.cookie = cookie;
+                            self.state = State::Server;
+                        }
+                        Err(_) => self.fail(format!("KeepAliveProtocol invalid cookie {}", cookie)),
+                    }
+                }
+                (Some(Value::Integer(1)), Some(Value::Integer(cookie))) if self.role == Agency::Client => {
+                    debug!("KeepAliveProtocol received MsgKeepAliveResponse {}", cookie);
+                    match u16::try_from(*cookie) {
+                        Ok(cookie) => self.keep_alive_response(cookie),
+                        Err(_) => self.fail(format!("KeepAliveProtocol invalid cookie {}", cookie)),
+                    }
+                }
+                (Some(Value::Integer(2)), None) if self.role == Agency::Server => {
+                    debug!("KeepAliveProtocol received MsgDone");
+                    self.state = State::Done;
+                    self.result = Some(Ok(format!("Answered {} keep-alives", self.answered)));
+                }
+                _ => self.fail(format!("Unexpected message: {:?}", cbor_array)),
+            },
+            _ => self.fail("Unexpected cbor!".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct RoundTrips(Arc<Mutex<Vec<u16>>>);
+
+    impl Listener for RoundTrips {
+        fn handle_round_trip(&mut self, cookie: u16, _rtt: Duration) {
+            self.0.lock().unwrap().push(cookie);
+        }
+    }
+
+    #[test]
+    fn keep_alive_works() {
+        let cookies = Arc::new(Mutex::new(Vec::new()));
+        let mut client = KeepAliveProtocol::new()
+            .with_interval(Duration::from_secs(0))
+            .with_count(3)
+            .with_listener(Box::new(RoundTrips(cookies.clone())));
+        let mut server = KeepAliveProtocol::expect();
+        assert_eq!(client.protocol_id(), 0x0008);
+        assert_eq!(server.protocol_id(), 0x8008);
+
+        let mut messages = Vec::new();
+        while client.agency() != Agency::None {
+            assert_eq!(client.agency(), server.agency());
+            if client.agency() == Agency::Client {
+                let message = client.send_data().unwrap();
+                messages.push(message.clone());
+                server.receive_data(message);
+            } else {
+                let message = server.send_data().unwrap();
+                messages.push(message.clone());
+                client.receive_data(message);
+            }
+        }
+
+        assert_eq!(server.agency(), Agency::None);
+        assert_eq!(messages, vec![
+            vec![0x82, 0x00, 0x00], vec![0x82, 0x01, 0x00],
+            vec![0x82, 0x00, 0x01], vec![0x82, 0x01, 0x01],
+            vec![0x82, 0x00, 0x02], vec![0x82, 0x01, 0x02],
+            vec![0x81, 0x02],
+        ]);
+        assert_eq!(client.result(), Ok("Received 3 responses".to_string()));
+        assert_eq!(server.result(), Ok("Answered 3 keep-alives".to_string()));
+        assert_eq!(client.round_trips().iter().map(|(cookie, _)| *cookie).collect::<Vec<u16>>(), vec![0, 1, 2]);
+        assert_eq!(*cookies.lock().unwrap(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn keep_alive_interval_works() {
+        let mut client = KeepAliveProtocol::new();
+        assert!(client.send_data().is_some());
+        client.receive_data(vec![0x82, 0x01, 0x00]);
+        assert_eq!(client.agency(), Agency::Client);
+        /* Next keep-alive is only due after the interval. */
+        assert_eq!(client.send_data(), None);
+        assert_eq!(client.agency(), Agency::Client);
+    }
+
+    #[test]
+    fn keep_alive_cookie_mismatch_works() {
+        let mut client = KeepAliveProtocol::new();
+        client.send_data();
+        client.receive_data(vec![0x82, 0x01, 0x05]);
+        assert_eq!(client.agency(), Agency::None);
+        assert!(client.result().unwrap_err().contains("cookie mismatch"));
+    }
+
+    #[test]
+    fn keep_alive_invalid_cookie_works() {
+        /* Cookie 0x10000 must not be mistaken for 0. */
+        let mut client = KeepAliveProtocol::new();
+        client.send_data();
+        client.receive_data(vec![0x82, 0x01, 0x1a, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(client.agency(), Agency::None);
+        assert!(client.result().unwrap_err().contains("invalid cookie 65536"));
+
+        let mut server = KeepAliveProtocol::expect();
+        server.receive_data(vec![0x82, 0x00, 0x1a, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(server.agency(), Agency::None);
+        assert!(server.result().unwrap_err().contains("invalid cookie 65536"));
+    }
+}
@@ -0,0 +1,299 @@
+/**
+© 2020 PERLUR Group
+
+SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
+
+*/
+
+use std::{
+    convert::TryFrom,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use log::{debug, error, warn};
+use serde_cbor::{de, ser, Value};
+
+use crate::{
+    Agency,
+    Protocol,
+};
+
+#[derive(Debug)]
+pub enum State {
+    Idle,
+    Busy,
+    Done,
+}
+
+pub struct PeerSharingProtocol {
+    role: Agency,
+    state: State,
+    result: Option<Result<String, String>>,
+    /* Number of peers asked for, or the peers to share when serving. */
+    amount: u8,
+    peers: Vec<SocketAddr>,
+    shared: usize,
+    is_requested: bool,
+}
+
+impl PeerSharingProtocol {
+    // Ask the peer once for up to amount addresses.
+    pub fn new(amount: u8) -> Self {
+        PeerSharingProtocol {
+            role: Agency::Client,
+            state: State::Idle,
+            result: None,
+            amount,
+            peers: Vec::new(),
+            shared: 0,
+            is_requested: false,
+        }
+    }
+
+    // Share addresses from the given ones on request.
+    pub fn expect(peers: Vec<SocketAddr>) -> Self {
+        PeerSharingProtocol {
+            role: Agency::Server,
+            peers,
+            ..PeerSharingProtocol::new(0)
+        }
+    }
+
+    // Addresses received from the peer.
+    pub fn peers(&self) -> &[SocketAddr] {
+        &self.peers
+    }
+
+    fn msg_share_request(&self) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(0), Value::Integer(self.amount as i128)])).unwrap()
+    }
+
+    fn msg_share_peers(&self) -> Vec<u8> {
+        let peers = self.peers.iter().take(self.amount as usize).map(encode_peer_address).collect();
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(1), Value::Array(peers)])).unwrap()
+    }
+
+    fn msg_done(&self) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(2)])).unwrap()
+    }
+
+    fn fail(&mut self, error: String) {
+        error!("{}", error);
+        self.state = State::Done;
+        self.result = Some(Err(error));
+    }
+
+    fn receive_peers(&mut self, addresses: &[Value]) {
+        for address in addresses {
+            match parse_peer_address(address) {
+                Some(peer) => self.peers.push(peer),
+                None => warn!("PeerSharingProtocol ignoring address {:?}", address),
+            }
+        }
+        if addresses.len() > self.amount as usize {
+            warn!("PeerSharingProtocol asked for {} peers, received {}", self.amount, addresses.len());
+        }
+        self.state = State::Idle;
+    }
+}
+
+impl Protocol for PeerSharingProtocol {
+    fn protocol_id(&self) -> u16 {
+        let idx: u16 = 0x000a;
+        match self.role {
+            Agency::Server => idx ^ 0x8000,
+            _ => idx,
+        }
+    }
+
+    fn result(&self) -> Result<String, String> {
+        self.result.clone().unwrap()
+    }
+
+    fn role(&self) -> Agency {
+        self.role
+    }
+
+    fn agency(&self) -> Agency {
+        match self.state {
+            State::Idle => Agency::Client,
+            State::Busy => Agency::Server,
+            State::Done => Agency::None,
+        }
+    }
+
+    fn state(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
+    fn send_data(&mut self) -> Option<Vec<u8>> {
+        match (&self.state, self.role) {
+            (State::Idle, Agency::Client) if !self.is_requested => {
+                debug!("PeerSharingProtocol sending MsgShareRequest {}", self.amount);
+                self.is_requested = true;
+                self.state = State::Busy;
+                Some(self.msg_share_request())
+            }
+            (State::Idle, Agency::Client) => {
+                debug!("PeerSharingProtocol sending MsgDone");
+                self.state = State::Done;
+                self.result = Some(Ok(format!("Received {} peers", self.peers.len())));
+                Some(self.msg_done())
+            }
+            (State::Busy, Agency::Server) => {
+                let payload = self.msg_share_peers();
+                self.shared += self.peers.len().min(self.amount as usize);
+                debug!("PeerSharingProtocol sending MsgSharePeers");
+                self.state = State::Idle;
+                Some(payload)
+            }
+            _ => {
+                warn!("PeerSharingProtocol::State::{:?}", self.state);
+                None
+            }
+        }
+    }
+
+    fn receive_data(&mut self, data: Vec<u8>) {
+        let cbor_value: Value = match de::from_slice(&data[..]) {
+            Ok(cbor_value) => cbor_value,
+            Err(err) => {
+                self.fail(format!("cbor decode error!: {}, hex: {}", err, hex::encode(&data)));
+                return;
+            }
+        };
+        //msgShareRequest = [0, byte]
+        //msgSharePeers   = [1, [* peerAddress]]
+        //msgDone         = [2]
+        match cbor_value {
+            Value::Array(cbor_array) => match (cbor_array.first(), cbor_array.get(1)) {
+                (Some(Value::Integer(0)), Some(Value::Integer(amount))) if self.role == Agency::Server => {
+                    debug!("PeerSharingProtocol received MsgShareRequest {}", amount);
+                    match u8::try_from(*amount) {
+                        Ok(amount) => {
+                            self.amount = amount;
+                            self.state = State::Busy;
+                        }
+                        Err(_) => self.fail(format!("PeerSharingProtocol invalid amount {}", amount)),
+                    }
+                }
+                (Some(Value::Integer(1)), Some(Value::Array(addresses))) if self.role == Agency::Client => {
+                    debug!("PeerSharingProtocol received MsgSharePeers");
+                    self.receive_peers(addresses);
+                }
+                (Some(Value::Integer(2)), None) if self.role == Agency::Server => {
+                    debug!("PeerSharingProtocol received MsgDone");
+                    self.state = State::Done;
+                    self.result = Some(Ok(format!("Shared {} peers", self.shared)));
+                }
+                _ => self.fail(format!("Unexpected message: {:?}", cbor_array)),
+            },
+            _ => self.fail("Unexpected cbor!".to_string()),
+        }
+    }
+}
+
+// IPv4 as [0, address, port], IPv6 as [1, address in four words, port]
+pub fn encode_peer_address(address: &SocketAddr) -> Value {
+    let port = Value::Integer(address.port() as i128);
+    match address {
+        SocketAddr::V4(address) => {
+            Value::Array(vec![Value::Integer(0), Value::Integer(u32::from(*address.ip()) as i128), port])
+        }
+        SocketAddr::V6(address) => {
+            let ip = u128::from(*address.ip());
+            let mut value = vec![Value::Integer(1)];
+            value.extend((0..4).rev().map(|word| Value::Integer((ip >> (word * 32)) as u32 as i128)));
+            value.push(port);
+            Value::Array(value)
+        }
+    }
+}
+
+pub fn parse_peer_address(value: &Value) -> Option<SocketAddr> {
+    let words = match value {
+        Value::Array(words) => words.iter().map(|word| match word {
+            Value::Integer(word) => u32::try_from(*word).ok(),
+            _ => None,
+        }).collect::<Option<Vec<u32>>>()?,
+        _ => return None,
+    };
+    match words[..] {
+        [0, ip, port] => Some(SocketAddr::new(Ipv4Addr::from(ip).into(), u16::try_from(port).ok()?)),
+        [1, ip0, ip1, ip2, ip3, port] => {
+            let ip = [ip0, ip1, ip2, ip3].iter().fold(0u128, |ip, word| ip << 32 | *word as u128);
+            Some(SocketAddr::new(Ipv6Addr::from(ip).into(), u16::try_from(port).ok()?))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses() -> Vec<SocketAddr> {
+        vec![
+            "10.0.0.1:3001".parse().unwrap(),
+            "[2001:db8::1]:6000".parse().unwrap(),
+            "192.168.1.2:3002".parse().unwrap(),
+        ]
+    }
+
+    #[test]
+    fn peer_address_works() {
+        assert_eq!(encode_peer_address(&addresses()[0]), Value::Array(vec![
+            Value::Integer(0), Value::Integer(0x0a000001), Value::Integer(3001),
+        ]));
+        assert_eq!(encode_peer_address(&addresses()[1]), Value::Array(vec![
+            Value::Integer(1), Value::Integer(0x20010db8), Value::Integer(0), Value::Integer(0), Value::Integer(1),
+            Value::Integer(6000),
+        ]));
+        for address in addresses() {
+            assert_eq!(parse_peer_address(&encode_peer_address(&address)), Some(address));
+        }
+        assert_eq!(parse_peer_address(&Value::Array(vec![
+            Value::Integer(0), Value::Integer(1), Value::Integer(70000),
+        ])), None);
+        assert_eq!(parse_peer_address(&Value::Array(vec![Value::Integer(2)])), None);
+    }
+
+    #[test]
+    fn peer_sharing_works() {
+        let mut client = PeerSharingProtocol::new(2);
+        let mut server = PeerSharingProtocol::expect(addresses());
+        assert_eq!(client.protocol_id(), 0x000a);
+        assert_eq!(server.protocol_id(), 0x800a);
+
+        while client.agency() != Agency::None {
+            assert_eq!(client.agency(), server.agency());
+            if client.agency() == Agency::Client {
+                server.receive_data(client.send_data().unwrap());
+            } else {
+                client.receive_data(server.send_data().unwrap());
+            }
+        }
+
+        assert_eq!(server.agency(), Agency::None);
+        assert_eq!(client.peers(), &addresses()[..2]);
+        assert_eq!(client.result(), Ok("Received 2 peers".to_string()));
+        assert_eq!(server.result(), Ok("Shared 2 peers".to_string()));
+    }
+
+    #[test]
+    fn peer_sharing_invalid_works() {
+        let mut client = PeerSharingProtocol::new(2);
+        client.send_data();
+        client.receive_data(vec![0x82, 0x00, 0x02]);
+        assert_eq!(client.agency(), Agency::None);
+        assert!(client.result().is_err());
+    }
+
+    #[test]
+    fn peer_sharing_invalid_amount_works() {
+        let mut server = PeerSharingProtocol::expect(addresses());
+        server.receive_data(ser::to_vec_packed(&Value::Array(vec![Value::Integer(0), Value::Integer(256)])).unwrap());
+        assert_eq!(server.agency(), Agency::None);
+        assert!(server.result().unwrap_err().contains("invalid amount 256"));
+    }
+}
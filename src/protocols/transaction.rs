@@ -9,107 +9,468 @@ SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 
 */
 
+use std::{
+    collections::VecDeque,
+    time::Duration,
+};
+
+use blake2b_simd::Params;
 use byteorder::WriteBytesExt;
 use log::{debug, error, warn};
-use serde_cbor::{de, Value};
+use serde::de::IgnoredAny;
+use serde_cbor::{de, ser, Deserializer, Value};
+use tokio::{sync::watch, time::sleep};
 
-use crate::{Agency, Protocol};
+use crate::{
+    Agency,
+    Protocol,
+    Ready,
+    block::Era,
+};
 
 #[derive(Debug)]
 pub enum State {
     Idle,
     TxIdsBlocking,
     TxIdsNonBlocking,
-    //Txs,
+    Txs,
     Done,
 }
 
+#[derive(Debug, PartialEq)]
+pub enum Mode {
+    // Offer transactions from a TxSource (client)
+    Submit,
+    // Pull transactions into a Listener (server)
+    Collect,
+}
+
+// Default number of transaction ids the server keeps unacknowledged
+pub const MAX_UNACKNOWLEDGED: usize = 10;
+// Default number of transactions the server requests at once
+pub const MAX_TXS_PER_REQUEST: usize = 3;
+
+// Signed transaction to submit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tx {
+    pub era: Era,
+    pub id: Vec<u8>,
+    pub size: u32,
+    pub body: Vec<u8>,
+}
+
+impl Tx {
+    // Id as exchanged between peers: [era, id]
+    fn id_value(&self) -> Value {
+        Value::Array(vec![Value::Integer(self.era as i128), Value::Bytes(self.id.clone())])
+    }
+}
+
+pub trait TxSource {
+    // Up to count transactions not offered before
+    fn next_txs(&mut self, count: usize) -> Vec<Tx>;
+
+    // Whether no more transactions will come, ending the protocol once the
+    // source is empty. Otherwise the protocol waits for it to have some.
+    fn is_done(&mut self) -> bool {
+        true
+    }
+
+    // Receiver notified whenever transactions are added or the source is
+    // done, None if the source has to be polled
+    fn subscribe(&mut self) -> Option<watch::Receiver<()>> {
+        None
+    }
+
+    // Transaction was pulled by the peer
+    fn handle_pulled(&mut self, _tx: &Tx) {}
+}
+
+pub trait Listener {
+    // Transaction pulled from the peer
+    fn handle_tx(&mut self, tx: &Tx);
+}
+
 pub struct TxSubmissionProtocol {
+    pub(crate) mode: Mode,
     pub(crate) state: State,
     pub(crate) result: Option<Result<String, String>>,
+    source: Option<Box<dyn TxSource>>,
+    /* Changes of the source, to wake up a blocking request without transactions. */
+    updates: Option<watch::Receiver<()>>,
+    /* Transactions offered (or announced to us) and not yet acknowledged, oldest first. */
+    unacknowledged: VecDeque<Tx>,
+    requested_ids: Vec<Value>,
+    requested_count: usize,
+    pulled: Vec<Vec<u8>>,
+
+    // Collect mode
+    notify: Option<Box<dyn Listener>>,
+    max_unacknowledged: usize,
+    max_txs_per_request: usize,
+    /* Leading unacknowledged transactions already received. */
+    fetched: usize,
+    collected: usize,
 }
 
 impl Default for TxSubmissionProtocol {
     fn default() -> Self {
-        TxSubmissionProtocol { state: State::Idle, result: None }
+        TxSubmissionProtocol {
+            mode: Mode::Submit,
+            state: State::Idle,
+            result: None,
+            source: None,
+            updates: None,
+            unacknowledged: VecDeque::new(),
+            requested_ids: Vec::new(),
+            requested_count: 0,
+            pulled: Vec::new(),
+            notify: None,
+            max_unacknowledged: MAX_UNACKNOWLEDGED,
+            max_txs_per_request: MAX_TXS_PER_REQUEST,
+            fetched: 0,
+            collected: 0,
+        }
     }
 }
 
 impl TxSubmissionProtocol {
-    fn msg_reply_tx_ids(&self) -> Vec<u8> {
-        // We need to do manual cbor encoding to do the empty indefinite array for txs.
-        // We always just tell the server we have no transactions to send it.
+    /* Polling interval of sources that don't notify about changes. */
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    pub fn new(mut source: Box<dyn TxSource>) -> Self {
+        TxSubmissionProtocol {
+            updates: source.subscribe(),
+            source: Some(source),
+            ..Default::default()
+        }
+    }
+
+    // Server pulling transactions into the listener, peers speaking node-to-node
+    // version 6 or later need TxSubmission2Protocol::collect() instead.
+    pub fn collect(notify: Box<dyn Listener>) -> Self {
+        TxSubmissionProtocol {
+            mode: Mode::Collect,
+            notify: Some(notify),
+            ..Default::default()
+        }
+    }
+
+    // Flow control of the collecting server: how many ids may be outstanding
+    // and how many transactions to request at once.
+    pub fn with_limits(mut self, max_unacknowledged: usize, max_txs_per_request: usize) -> Self {
+        self.max_unacknowledged = max_unacknowledged.max(1);
+        self.max_txs_per_request = max_txs_per_request.max(1);
+        self
+    }
+
+    // Ids of the transactions pulled by the peer.
+    pub fn pulled(&self) -> &[Vec<u8>] {
+        &self.pulled
+    }
+
+    // Number of transactions collected from the peer.
+    pub fn collected(&self) -> usize {
+        self.collected
+    }
+
+    fn is_source_done(&mut self) -> bool {
+        match self.source.as_mut() {
+            Some(source) => source.is_done(),
+            None => true,
+        }
+    }
+
+    fn next_txs(&mut self) -> Vec<Tx> {
+        let count = self.requested_count;
+        match self.source.as_mut() {
+            Some(source) if count > 0 => source.next_txs(count).into_iter().take(count).collect(),
+            _ => vec![],
+        }
+    }
+
+    fn msg_reply_tx_ids(&self, txs: &[Tx]) -> Vec<u8> {
+        // We need to do manual cbor encoding to do the indefinite array for txs.
         let mut message: Vec<u8> = Vec::new();
         message.write_u8(0x82).unwrap(); // array of length 2
         message.write_u8(0x01).unwrap(); // message id for ReplyTxIds is 1
         message.write_u8(0x9f).unwrap(); // indefinite array start
+        for tx in txs {
+            let id_and_size = Value::Array(vec![tx.id_value(), Value::Integer(tx.size as i128)]);
+            message.append(&mut ser::to_vec_packed(&id_and_size).unwrap());
+        }
         message.write_u8(0xff).unwrap(); // indefinite array end
-        return message;
+        message
     }
-}
 
-impl Protocol for TxSubmissionProtocol {
-    fn protocol_id(&self) -> u16 {
-        return 0x0004u16;
+    fn msg_reply_txs(&self, txs: &[&Tx]) -> Vec<u8> {
+        let mut message: Vec<u8> = Vec::new();
+        message.write_u8(0x82).unwrap(); // array of length 2
+        message.write_u8(0x03).unwrap(); // message id for ReplyTxs is 3
+        message.write_u8(0x9f).unwrap(); // indefinite array start
+        for tx in txs {
+            message.write_u8(0x82).unwrap(); // [era, #6.24(bytes)]
+            message.append(&mut ser::to_vec_packed(&(tx.era as u8)).unwrap());
+            message.write_u8(0xd8).unwrap(); // tag 24: CBOR in CBOR
+            message.write_u8(0x18).unwrap();
+            message.append(&mut ser::to_vec_packed(&Value::Bytes(tx.body.clone())).unwrap());
+        }
+        message.write_u8(0xff).unwrap(); // indefinite array end
+        message
     }
 
-    fn result(&self) -> Result<String, String> {
-        self.result.clone().unwrap()
+    fn msg_request_tx_ids(&self, is_blocking: bool, ack: usize, req: usize) -> Vec<u8> {
+        let message = Value::Array(vec![
+            Value::Integer(0),
+            Value::Bool(is_blocking),
+            Value::Integer(ack as i128),
+            Value::Integer(req as i128),
+        ]);
+        ser::to_vec_packed(&message).unwrap()
     }
 
-    fn role(&self) -> Agency {
-        Agency::Client
+    fn msg_request_txs(&self, ids: Vec<Value>) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(2), Value::Array(ids)])).unwrap()
     }
 
-    fn agency(&self) -> Agency {
-        return match self.state {
-            State::Idle => { Agency::Server }
-            State::TxIdsBlocking => { Agency::Client }
-            State::TxIdsNonBlocking => { Agency::Client }
-            State::Done => {
-                if self.result.is_none() { Agency::Client } else { Agency::None }
+    fn msg_done(&self) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(4)])).unwrap()
+    }
+
+    fn reply_txs(&mut self) -> Vec<u8> {
+        let requested_ids = std::mem::take(&mut self.requested_ids);
+        let txs: Vec<&Tx> = requested_ids.iter()
+            .filter_map(|id| self.unacknowledged.iter().find(|tx| tx.id_value() == *id))
+            .collect();
+        if txs.len() < requested_ids.len() {
+            warn!("TxSubmissionProtocol requested {} unknown transactions", requested_ids.len() - txs.len());
+        }
+        let payload = self.msg_reply_txs(&txs);
+        let txs: Vec<Tx> = txs.into_iter().cloned().collect();
+        for tx in txs {
+            if let Some(source) = self.source.as_mut() {
+                source.handle_pulled(&tx);
             }
-        };
+            self.pulled.push(tx.id);
+        }
+        payload
     }
 
-    fn state(&self) -> String {
-        format!("{:?}", self.state)
+    fn acknowledge(&mut self, count: usize) {
+        if count > self.unacknowledged.len() {
+            warn!("TxSubmissionProtocol acknowledged {} of {} transactions", count, self.unacknowledged.len());
+        }
+        let count = count.min(self.unacknowledged.len());
+        self.unacknowledged.drain(..count);
     }
 
-    fn send_data(&mut self) -> Option<Vec<u8>> {
-        return match self.state {
+    fn submit_data(&mut self) -> Option<Vec<u8>> {
+        match self.state {
             State::Idle => {
                 debug!("TxSubmissionProtocol::State::Idle");
                 None
             }
             State::TxIdsBlocking => {
                 debug!("TxSubmissionProtocol::State::TxIdsBlocking");
-                // Server will wait on us forever. Just move to Done state.
-                self.state = State::Done;
-                None
+                /* Changes from here on wake us up, if we have to wait. */
+                if let Some(updates) = self.updates.as_mut() {
+                    updates.borrow_and_update();
+                }
+                let txs = self.next_txs();
+                if txs.is_empty() && !self.is_source_done() {
+                    /* Blocking requests can't be answered without transactions. */
+                    return None;
+                }
+                if txs.is_empty() {
+                    // Server would wait on us forever, so we are done.
+                    self.state = State::Done;
+                    self.result = Some(Ok(format!("Submitted {} transactions", self.pulled.len())));
+                    return Some(self.msg_done());
+                }
+                let payload = self.msg_reply_tx_ids(&txs);
+                self.unacknowledged.extend(txs);
+                self.state = State::Idle;
+                Some(payload)
             }
             State::TxIdsNonBlocking => {
                 debug!("TxSubmissionProtocol::State::TxIdsNonBlocking");
-                // Tell the server that we have no transactions to send them
-                let payload = self.msg_reply_tx_ids();
+                let txs = self.next_txs();
+                let payload = self.msg_reply_tx_ids(&txs);
+                self.unacknowledged.extend(txs);
+                self.state = State::Idle;
+                Some(payload)
+            }
+            State::Txs => {
+                debug!("TxSubmissionProtocol::State::Txs");
+                let payload = self.reply_txs();
                 self.state = State::Idle;
                 Some(payload)
             }
-            //State::Txs => { None }
             State::Done => {
                 warn!("TxSubmissionProtocol::State::Done");
-                self.result = Option::Some(Ok(String::from("Done")));
                 None
             }
+        }
+    }
+
+    fn collect_data(&mut self) -> Option<Vec<u8>> {
+        match self.state {
+            State::Idle => {
+                debug!("TxSubmissionProtocol::State::Idle");
+                let announced = self.unacknowledged.len() - self.fetched;
+                if self.fetched > 0 && announced > 0 {
+                    /* Acknowledge what we got and top up the window while bodies are still to fetch. */
+                    let ack = self.fetched;
+                    self.acknowledge(ack);
+                    self.fetched = 0;
+                    self.state = State::TxIdsNonBlocking;
+                    return Some(self.msg_request_tx_ids(false, ack, self.max_unacknowledged.saturating_sub(announced)));
+                }
+                if announced > 0 {
+                    let count = announced.min(self.max_txs_per_request);
+                    let ids = self.unacknowledged.iter().skip(self.fetched).take(count).map(Tx::id_value).collect();
+                    self.requested_count = count;
+                    self.state = State::Txs;
+                    return Some(self.msg_request_txs(ids));
+                }
+                /* Nothing outstanding, wait for the peer to have new transactions. */
+                let ack = self.fetched;
+                self.acknowledge(ack);
+                self.fetched = 0;
+                self.state = State::TxIdsBlocking;
+                Some(self.msg_request_tx_ids(true, ack, self.max_unacknowledged))
+            }
+            _ => None,
+        }
+    }
+
+    fn receive_tx_ids(&mut self, cbor_array: &[Value]) {
+        let ids = match cbor_array.get(1) {
+            Some(Value::Array(ids)) => ids,
+            _ => {
+                error!("Unexpected cbor!");
+                return;
+            }
+        };
+        for id_and_size in ids {
+            match parse_tx_id_and_size(id_and_size) {
+                Some(tx) => self.unacknowledged.push_back(tx),
+                None => warn!("TxSubmissionProtocol ignoring tx id {:?}", id_and_size),
+            }
+        }
+        if ids.is_empty() && matches!(self.state, State::TxIdsBlocking) {
+            warn!("TxSubmissionProtocol received no tx ids on a blocking request");
+        }
+        self.state = State::Idle;
+    }
+
+    fn receive_txs(&mut self, cbor_array: &[Value]) {
+        let txs = match cbor_array.get(1) {
+            Some(Value::Array(txs)) => txs,
+            _ => {
+                error!("Unexpected cbor!");
+                return;
+            }
         };
+        /* Peers may leave out transactions that became invalid meanwhile. */
+        let requested = self.fetched..self.fetched + self.requested_count;
+        for value in txs {
+            let (era, body) = match parse_tx(value) {
+                Some(tx) => tx,
+                None => {
+                    warn!("TxSubmissionProtocol ignoring tx {:?}", value);
+                    continue;
+                }
+            };
+            let id = tx_id(&body);
+            let announced = self.unacknowledged.range(requested.clone())
+                .find(|tx| Some(&tx.id) == id.as_ref());
+            let tx = match announced {
+                Some(announced) => Tx { era, body, ..announced.clone() },
+                None => {
+                    warn!("TxSubmissionProtocol received a tx that was not requested");
+                    continue;
+                }
+            };
+            self.collected += 1;
+            if let Some(notify) = self.notify.as_mut() {
+                notify.handle_tx(&tx);
+            }
+        }
+        self.fetched += self.requested_count;
+        self.requested_count = 0;
+        self.state = State::Idle;
+    }
+}
+
+impl Protocol for TxSubmissionProtocol {
+    fn protocol_id(&self) -> u16 {
+        let idx: u16 = 0x0004;
+        match self.role() {
+            Agency::Server => idx ^ 0x8000,
+            _ => idx,
+        }
+    }
+
+    fn result(&self) -> Result<String, String> {
+        self.result.clone().unwrap()
+    }
+
+    fn role(&self) -> Agency {
+        match self.mode {
+            Mode::Submit => Agency::Client,
+            Mode::Collect => Agency::Server,
+        }
+    }
+
+    fn agency(&self) -> Agency {
+        return match self.state {
+            State::Idle => { Agency::Server }
+            State::TxIdsBlocking => { Agency::Client }
+            State::TxIdsNonBlocking => { Agency::Client }
+            State::Txs => { Agency::Client }
+            State::Done => { Agency::None }
+        };
+    }
+
+    fn state(&self) -> String {
+        format!("{:?}", self.state)
+    }
+
+    fn send_data(&mut self) -> Option<Vec<u8>> {
+        match self.mode {
+            Mode::Submit => self.submit_data(),
+            Mode::Collect => self.collect_data(),
+        }
+    }
+
+    fn ready(&mut self) -> Option<Ready> {
+        match (&self.mode, &self.state) {
+            (Mode::Submit, State::TxIdsBlocking) => Some(match self.updates.clone() {
+                Some(mut updates) => Box::pin(async move {
+                    if updates.changed().await.is_err() {
+                        /* The source stopped notifying, ask it again later. */
+                        sleep(TxSubmissionProtocol::POLL_INTERVAL).await
+                    }
+                }),
+                None => Box::pin(sleep(TxSubmissionProtocol::POLL_INTERVAL)),
+            }),
+            _ => None,
+        }
     }
 
     fn receive_data(&mut self, data: Vec<u8>) {
-        let cbor_value: Value = de::from_slice(&data[..]).unwrap();
+        let cbor_value: Value = match de::from_slice(&data[..]) {
+            Ok(cbor_value) => cbor_value,
+            Err(err) => {
+                error!("cbor decode error!: {}, hex: {}", err, hex::encode(&data));
+                return;
+            }
+        };
         match cbor_value {
             Value::Array(cbor_array) => {
-                match cbor_array[0] {
-                    Value::Integer(message_id) => {
+                match cbor_array.first() {
+                    Some(Value::Integer(message_id)) => {
                         match message_id {
                             //msgRequestTxIds = [0, tsBlocking, txCount, txCount]
                             //msgReplyTxIds   = [1, [ *txIdAndSize] ]
@@ -119,13 +480,42 @@ impl Protocol for TxSubmissionProtocol {
                             //msgReplyKTnxBye = [5]
                             0 => {
                                 debug!("TxSubmissionProtocol received MsgRequestTxIds");
-                                let is_blocking = cbor_array[1] == Value::Bool(true);
-                                self.state = if is_blocking {
-                                    State::TxIdsBlocking
-                                } else {
-                                    State::TxIdsNonBlocking
+                                match (cbor_array.get(1), cbor_array.get(2), cbor_array.get(3)) {
+                                    (Some(Value::Bool(is_blocking)), Some(Value::Integer(ack)), Some(Value::Integer(req))) => {
+                                        self.acknowledge(*ack as usize);
+                                        self.requested_count = *req as usize;
+                                        self.state = if *is_blocking {
+                                            State::TxIdsBlocking
+                                        } else {
+                                            State::TxIdsNonBlocking
+                                        }
+                                    }
+                                    _ => error!("Unexpected cbor!"),
+                                }
+                            }
+                            2 => {
+                                debug!("TxSubmissionProtocol received MsgRequestTxs");
+                                match cbor_array.get(1) {
+                                    Some(Value::Array(ids)) => {
+                                        self.requested_ids = ids.clone();
+                                        self.state = State::Txs;
+                                    }
+                                    _ => error!("Unexpected cbor!"),
                                 }
                             }
+                            1 => {
+                                debug!("TxSubmissionProtocol received MsgReplyTxIds");
+                                self.receive_tx_ids(&cbor_array);
+                            }
+                            3 => {
+                                debug!("TxSubmissionProtocol received MsgReplyTxs");
+                                self.receive_txs(&cbor_array);
+                            }
+                            4 => {
+                                debug!("TxSubmissionProtocol received MsgDone");
+                                self.state = State::Done;
+                                self.result = Some(Ok(format!("Collected {} transactions", self.collected)));
+                            }
                             _ => {
                                 error!("unexpected message_id: {}", message_id);
                             }
@@ -142,3 +532,328 @@ impl Protocol for TxSubmissionProtocol {
         }
     }
 }
+
+// Id of a transaction, the hash of its body: [body, witnesses, ...]
+pub fn tx_id(tx: &[u8]) -> Option<Vec<u8>> {
+    match tx {
+        [0x80..=0x97, ..] => {
+            let mut values = Deserializer::from_slice(&tx[1..]).into_iter::<IgnoredAny>();
+            values.next()?.ok()?;
+            let body = &tx[1..1 + values.byte_offset()];
+            Some(Params::new().hash_length(32).to_state().update(body).finalize().as_bytes().to_vec())
+        }
+        _ => None,
+    }
+}
+
+// Announced transaction without body: [[era, id], size]
+fn parse_tx_id_and_size(value: &Value) -> Option<Tx> {
+    match value {
+        Value::Array(id_and_size) => match (id_and_size.first(), id_and_size.get(1)) {
+            (Some(Value::Array(id)), Some(Value::Integer(size))) => match (id.first(), id.get(1)) {
+                (Some(Value::Integer(era)), Some(Value::Bytes(id))) => Some(Tx {
+                    era: Era::from_index(*era)?,
+                    id: id.clone(),
+                    size: *size as u32,
+                    body: vec![],
+                }),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Transaction as sent in MsgReplyTxs: [era, #6.24(bytes)]
+fn parse_tx(value: &Value) -> Option<(Era, Vec<u8>)> {
+    match value {
+        Value::Array(tx) => match (tx.first(), tx.get(1)) {
+            (Some(Value::Integer(era)), Some(Value::Bytes(body))) => Some((Era::from_index(*era)?, body.clone())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mux::tcp::Channel;
+    use futures::FutureExt;
+    use std::sync::{Arc, Mutex};
+
+    struct Source {
+        txs: Vec<Tx>,
+        pulled: Arc<Mutex<Vec<Tx>>>,
+    }
+
+    impl TxSource for Source {
+        fn next_txs(&mut self, count: usize) -> Vec<Tx> {
+            let count = count.min(self.txs.len());
+            self.txs.drain(..count).collect()
+        }
+
+        fn handle_pulled(&mut self, tx: &Tx) {
+            self.pulled.lock().unwrap().push(tx.clone());
+        }
+    }
+
+    fn tx(id: u8) -> Tx {
+        Tx { era: Era::Babbage, id: vec![id; 32], size: 2, body: vec![0x80 + id] }
+    }
+
+    fn request_tx_ids(blocking: bool, ack: i128, req: i128) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![
+            Value::Integer(0), Value::Bool(blocking), Value::Integer(ack), Value::Integer(req),
+        ])).unwrap()
+    }
+
+    fn reply(data: Vec<u8>) -> Value {
+        de::from_slice(&data).unwrap()
+    }
+
+    #[test]
+    fn tx_submission_client_works() {
+        let pulled = Arc::new(Mutex::new(Vec::new()));
+        let mut client = TxSubmissionProtocol::new(Box::new(Source {
+            txs: vec![tx(1), tx(2), tx(3)],
+            pulled: pulled.clone(),
+        }));
+        assert_eq!(client.agency(), Agency::Server);
+
+        client.receive_data(request_tx_ids(true, 0, 2));
+        assert_eq!(reply(client.send_data().unwrap()), Value::Array(vec![
+            Value::Integer(1),
+            Value::Array(vec![
+                Value::Array(vec![tx(1).id_value(), Value::Integer(2)]),
+                Value::Array(vec![tx(2).id_value(), Value::Integer(2)]),
+            ]),
+        ]));
+
+        client.receive_data(ser::to_vec_packed(&Value::Array(vec![
+            Value::Integer(2),
+            Value::Array(vec![tx(2).id_value()]),
+        ])).unwrap());
+        assert_eq!(client.send_data().unwrap(), vec![0x82, 0x03, 0x9f, 0x82, 0x05, 0xd8, 0x18, 0x41, 0x82, 0xff]);
+
+        /* Acknowledged transactions can't be requested anymore. */
+        client.receive_data(request_tx_ids(false, 2, 5));
+        assert_eq!(reply(client.send_data().unwrap()), Value::Array(vec![
+            Value::Integer(1),
+            Value::Array(vec![Value::Array(vec![tx(3).id_value(), Value::Integer(2)])]),
+        ]));
+        client.receive_data(ser::to_vec_packed(&Value::Array(vec![
+            Value::Integer(2),
+            Value::Array(vec![tx(1).id_value(), tx(3).id_value()]),
+        ])).unwrap());
+        assert_eq!(reply(client.send_data().unwrap()), Value::Array(vec![
+            Value::Integer(3),
+            Value::Array(vec![Value::Array(vec![Value::Integer(5), Value::Bytes(vec![0x83])])]),
+        ]));
+
+        client.receive_data(request_tx_ids(true, 1, 1));
+        assert_eq!(client.send_data().unwrap(), client.msg_done());
+        assert_eq!(client.agency(), Agency::None);
+        assert_eq!(client.result(), Ok("Submitted 2 transactions".to_string()));
+        assert_eq!(client.pulled(), &[vec![2; 32], vec![3; 32]][..]);
+        assert_eq!(*pulled.lock().unwrap(), vec![tx(2), tx(3)]);
+    }
+
+    /* Source filled up over time, done once told so, announcing changes if subscribed. */
+    #[derive(Clone)]
+    struct Mempool(Arc<Mutex<(Vec<Tx>, bool)>>, Option<watch::Receiver<()>>);
+
+    impl TxSource for Mempool {
+        fn next_txs(&mut self, count: usize) -> Vec<Tx> {
+            let mut mempool = self.0.lock().unwrap();
+            let count = count.min(mempool.0.len());
+            mempool.0.drain(..count).collect()
+        }
+
+        fn is_done(&mut self) -> bool {
+            self.0.lock().unwrap().1
+        }
+
+        fn subscribe(&mut self) -> Option<watch::Receiver<()>> {
+            self.1.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn tx_submission_client_waits_for_txs() {
+        let mempool = Mempool(Arc::new(Mutex::new((vec![], false))), None);
+        let mut client = TxSubmissionProtocol::new(Box::new(mempool.clone()));
+
+        client.receive_data(request_tx_ids(true, 0, 2));
+        assert_eq!(client.send_data(), None);
+        assert_eq!(client.agency(), Agency::Client);
+        assert!(client.ready().is_some());
+
+        mempool.0.lock().unwrap().0.push(tx(1));
+        assert_eq!(reply(client.send_data().unwrap()), Value::Array(vec![
+            Value::Integer(1),
+            Value::Array(vec![Value::Array(vec![tx(1).id_value(), Value::Integer(2)])]),
+        ]));
+
+        client.receive_data(request_tx_ids(true, 1, 2));
+        assert_eq!(client.send_data(), None);
+        mempool.0.lock().unwrap().1 = true;
+        assert_eq!(client.send_data().unwrap(), client.msg_done());
+        assert_eq!(client.agency(), Agency::None);
+    }
+
+    #[test]
+    fn tx_submission_client_wakes_up_works() {
+        let (updates, subscription) = watch::channel(());
+        let mempool = Mempool(Arc::new(Mutex::new((vec![], false))), Some(subscription));
+        let mut client = TxSubmissionProtocol::new(Box::new(mempool.clone()));
+
+        client.receive_data(request_tx_ids(true, 0, 2));
+        assert_eq!(client.send_data(), None);
+        let mut ready = client.ready().unwrap();
+        assert!(ready.as_mut().now_or_never().is_none());
+
+        /* Woken up by the source instead of waiting for the next poll. */
+        mempool.0.lock().unwrap().0.push(tx(1));
+        updates.send(()).unwrap();
+        assert!(ready.now_or_never().is_some());
+        assert!(client.send_data().is_some());
+    }
+
+    struct Collector(Arc<Mutex<Vec<Tx>>>);
+
+    impl Listener for Collector {
+        fn handle_tx(&mut self, tx: &Tx) {
+            self.0.lock().unwrap().push(tx.clone());
+        }
+    }
+
+    fn signed_tx(fee: u8) -> Tx {
+        /* [{2: fee}, {}, true, null] */
+        let body = vec![0x84, 0xa1, 0x02, fee, 0xa0, 0xf5, 0xf6];
+        Tx { era: Era::Conway, id: tx_id(&body).unwrap(), size: body.len() as u32, body }
+    }
+
+    #[test]
+    fn tx_id_works() {
+        assert_eq!(tx_id(&signed_tx(1).body), Some(
+            Params::new().hash_length(32).to_state().update(&[0xa1, 0x02, 0x01]).finalize().as_bytes().to_vec()
+        ));
+        assert_eq!(tx_id(&[0xa0]), None);
+    }
+
+    #[test]
+    fn tx_submission_collect_works() {
+        let txs: Vec<Tx> = (1..=5).map(signed_tx).collect();
+        let pulled = Arc::new(Mutex::new(Vec::new()));
+        let collected = Arc::new(Mutex::new(Vec::new()));
+        let mut client = TxSubmissionProtocol::new(Box::new(Source { txs: txs.clone(), pulled: pulled.clone() }));
+        let mut server = TxSubmissionProtocol::collect(Box::new(Collector(collected.clone()))).with_limits(4, 2);
+        assert_eq!(server.role(), Agency::Server);
+
+        let mut requests = Vec::new();
+        while client.agency() != Agency::None {
+            assert_eq!(client.agency(), server.agency());
+            if client.agency() == Agency::Server {
+                let request = server.send_data().unwrap();
+                requests.push(reply(request.clone()));
+                client.receive_data(request);
+            } else {
+                server.receive_data(client.send_data().unwrap());
+            }
+        }
+
+        assert_eq!(server.agency(), Agency::None);
+        assert_eq!(server.result(), Ok("Collected 5 transactions".to_string()));
+        assert_eq!(client.result(), Ok("Submitted 5 transactions".to_string()));
+        assert_eq!(*collected.lock().unwrap(), txs);
+        assert_eq!(*pulled.lock().unwrap(), txs);
+
+        let request_tx_ids = |blocking, ack, req| Value::Array(vec![
+            Value::Integer(0), Value::Bool(blocking), Value::Integer(ack), Value::Integer(req),
+        ]);
+        let request_txs = |ids: &[usize]| Value::Array(vec![
+            Value::Integer(2), Value::Array(ids.iter().map(|i| txs[*i].id_value()).collect()),
+        ]);
+        assert_eq!(requests, vec![
+            request_tx_ids(true, 0, 4),
+            request_txs(&[0, 1]),
+            request_tx_ids(false, 2, 2),
+            request_txs(&[2, 3]),
+            request_tx_ids(false, 2, 3),
+            request_txs(&[4]),
+            request_tx_ids(true, 1, 4),
+        ]);
+    }
+
+    struct NoTxs;
+
+    impl TxSource for NoTxs {
+        fn next_txs(&mut self, _count: usize) -> Vec<Tx> {
+            vec![]
+        }
+    }
+
+    impl Listener for NoTxs {
+        fn handle_tx(&mut self, _tx: &Tx) {}
+    }
+
+    #[tokio::test]
+    async fn tx_submission_responder_works() {
+        let (client, server) = tokio::io::duplex(1024);
+
+        let cli = async move {
+            let client = Channel::new(client);
+            let result = client.execute(TxSubmissionProtocol::new(Box::new(NoTxs))).await;
+            assert_eq!(result, Ok("Submitted 0 transactions".to_string()));
+        };
+        let srv = async move {
+            let server = Channel::new(server);
+            let result = server.execute(TxSubmissionProtocol::collect(Box::new(NoTxs))).await;
+            assert_eq!(result, Ok("Collected 0 transactions".to_string()));
+        };
+
+        tokio::join!(cli, srv);
+    }
+
+    #[tokio::test]
+    async fn tx_submission_responder_waits_for_txs() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (updates, subscription) = watch::channel(());
+        let mempool = Mempool(Arc::new(Mutex::new((vec![], false))), Some(subscription));
+        let collected = Arc::new(Mutex::new(Vec::new()));
+
+        let cli = {
+            let mempool = mempool.clone();
+            async move {
+                let client = Channel::new(client);
+                let result = client.execute(TxSubmissionProtocol::new(Box::new(mempool))).await;
+                assert_eq!(result, Ok("Submitted 2 transactions".to_string()));
+            }
+        };
+        let srv = {
+            let collected = collected.clone();
+            async move {
+                let server = Channel::new(server);
+                let result = server.execute(TxSubmissionProtocol::collect(Box::new(Collector(collected)))).await;
+                assert_eq!(result, Ok("Collected 2 transactions".to_string()));
+            }
+        };
+        /* Transactions show up after the first request, then the mempool is closed. */
+        let submit = async move {
+            for fee in 1..=2 {
+                sleep(Duration::from_millis(150)).await;
+                mempool.0.lock().unwrap().0.push(signed_tx(fee));
+                updates.send(()).unwrap();
+            }
+            sleep(Duration::from_millis(150)).await;
+            mempool.0.lock().unwrap().1 = true;
+            updates.send(()).unwrap();
+        };
+
+        let sync = async { tokio::join!(cli, srv, submit) };
+        assert!(tokio::time::timeout(Duration::from_secs(5), sync).await.is_ok());
+        assert_eq!(*collected.lock().unwrap(), vec![signed_tx(1), signed_tx(2)]);
+    }
+}
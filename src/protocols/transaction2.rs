@@ -9,151 +9,237 @@ SPDX-License-Identifier: GPL-3.0-only OR LGPL-3.0-only
 
 */
 
-use byteorder::WriteBytesExt;
-use log::{debug, error, warn};
-use serde_cbor::{de, Value};
-
-use crate::{Agency, Protocol};
-
-#[derive(Debug)]
-pub enum State {
-    Hello,
-    Idle,
-    TxIdsBlocking,
-    TxIdsNonBlocking,
-    Txs,
-    Done,
-}
-
+use log::{debug, error};
+use serde_cbor::{de, ser, Value};
+
+use crate::{
+    Agency,
+    Protocol,
+    Ready,
+    protocols::{
+        handshake::{PROTOCOL_VERSION_MARY, PROTOCOL_VERSION_N2C_MASK},
+        transaction::{Listener, State, TxSource, TxSubmissionProtocol},
+    },
+};
+
+// TxSubmission2 is the only TxSubmission from this node-to-node version on.
+pub const MIN_TX_SUBMISSION2_VERSION: u16 = PROTOCOL_VERSION_MARY;
+
+// TxSubmission2 differs from the original protocol only by the client
+// introducing itself with MsgHello, after that the server is in charge.
 pub struct TxSubmission2Protocol {
-    pub(crate) state: State,
-    pub(crate) result: Option<Result<String, String>>,
+    hello: bool,
+    inner: TxSubmissionProtocol,
 }
 
 impl Default for TxSubmission2Protocol {
     fn default() -> Self {
-        TxSubmission2Protocol { state: State::Hello, result: None }
+        TxSubmission2Protocol { hello: true, inner: TxSubmissionProtocol::default() }
     }
 }
 
 impl TxSubmission2Protocol {
-    fn msg_reply_tx_ids(&self) -> Vec<u8> {
-        // We need to do manual cbor encoding to do the empty indefinite array for txs.
-        // We always just tell the server we have no transactions to send it.
-        let mut message: Vec<u8> = Vec::new();
-        message.write_u8(0x82).unwrap(); // array of length 2
-        message.write_u8(0x01).unwrap(); // message id for ReplyTxIds is 1
-        message.write_u8(0x9f).unwrap(); // indefinite array start
-        message.write_u8(0xff).unwrap(); // indefinite array end
-        return message;
+    pub fn new(source: Box<dyn TxSource>) -> Self {
+        TxSubmission2Protocol { hello: true, inner: TxSubmissionProtocol::new(source) }
+    }
+
+    // Speak the TxSubmission variant of the negotiated handshake version,
+    // node-to-client connections submit with LocalTxSubmission instead.
+    pub fn for_version(version: u16, source: Box<dyn TxSource>) -> Result<Self, String> {
+        if version & PROTOCOL_VERSION_N2C_MASK != 0 {
+            return Err(format!("TxSubmission is not part of node-to-client version {:04x}", version));
+        }
+        Ok(TxSubmission2Protocol {
+            hello: version >= MIN_TX_SUBMISSION2_VERSION,
+            inner: TxSubmissionProtocol::new(source),
+        })
+    }
+
+    // Server collecting transactions once the client said hello.
+    pub fn collect(notify: Box<dyn Listener>) -> Self {
+        TxSubmission2Protocol { hello: true, inner: TxSubmissionProtocol::collect(notify) }
+    }
+
+    // See TxSubmissionProtocol::with_limits().
+    pub fn with_limits(mut self, max_unacknowledged: usize, max_txs_per_request: usize) -> Self {
+        self.inner = self.inner.with_limits(max_unacknowledged, max_txs_per_request);
+        self
+    }
+
+    // Ids of the transactions pulled by the peer.
+    pub fn pulled(&self) -> &[Vec<u8>] {
+        self.inner.pulled()
+    }
+
+    // Number of transactions collected from the peer.
+    pub fn collected(&self) -> usize {
+        self.inner.collected()
+    }
+
+    fn msg_hello(&self) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![Value::Integer(6)])).unwrap()
     }
 }
 
 impl Protocol for TxSubmission2Protocol {
     fn protocol_id(&self) -> u16 {
-        return 0x0004u16;
+        self.inner.protocol_id()
     }
 
     fn result(&self) -> Result<String, String> {
-        self.result.clone().unwrap()
+        self.inner.result()
     }
 
     fn role(&self) -> Agency {
-        Agency::Client
+        self.inner.role()
     }
 
     fn agency(&self) -> Agency {
-        return match self.state {
-            State::Idle => { Agency::Server }
-            State::TxIdsBlocking => { Agency::Client }
-            State::TxIdsNonBlocking => { Agency::Client }
-            State::Done => {
-                if self.result.is_none() { Agency::Client } else { Agency::None }
-            }
-            State::Hello => { Agency::None }
-            State::Txs => { Agency::None }			// TODO: change to the correct Agency
-        };
+        if self.hello { Agency::Client } else { self.inner.agency() }
     }
 
     fn state(&self) -> String {
-        format!("{:?}", self.state)
+        if self.hello { "Hello".to_string() } else { self.inner.state() }
     }
 
     fn send_data(&mut self) -> Option<Vec<u8>> {
-        return match self.state {
-            State::Idle => {
-                debug!("TxSubmission2Protocol::State::Idle");
-                None
-            }
-            State::TxIdsBlocking => {
-                debug!("TxSubmission2Protocol::State::TxIdsBlocking");
-                // Server will wait on us forever. Just move to Done state.
-                self.state = State::Done;
-                None
-            }
-            State::TxIdsNonBlocking => {
-                debug!("TxSubmission2Protocol::State::TxIdsNonBlocking");
-                // Tell the server that we have no transactions to send them
-                let payload = self.msg_reply_tx_ids();
-                self.state = State::Idle;
-                Some(payload)
-            }
-            State::Txs => { 
-				debug!("TxSubmission2Protocol::State::Txs");
-				None 
-			}
-            State::Done => {
-                warn!("TxSubmission2Protocol::State::Done");
-                self.result = Option::Some(Ok(String::from("Done")));
-                None
-            }
-            State::Hello => { 
-				debug!("TxSubmission2Protocol::State::Hello");
-				None 
-			}
-        };
+        if self.hello && self.role() == Agency::Client {
+            debug!("TxSubmission2Protocol::State::Hello");
+            self.hello = false;
+            return Some(self.msg_hello());
+        }
+        self.inner.send_data()
+    }
+
+    fn ready(&mut self) -> Option<Ready> {
+        self.inner.ready()
     }
 
     fn receive_data(&mut self, data: Vec<u8>) {
-        let cbor_value: Value = de::from_slice(&data[..]).unwrap();
-        match cbor_value {
-            Value::Array(cbor_array) => {
-                match cbor_array[0] {
-                    Value::Integer(message_id) => {
-                        match message_id {
-                            //msgRequestTxIds = [0, tsBlocking, txCount, txCount]
-                            //msgReplyTxIds   = [1, [ *txIdAndSize] ]
-                            //msgRequestTxs   = [2, tsIdList ]
-                            //msgReplyTxs     = [3, tsIdList ]
-                            //tsMsgDone       = [4]
-                            //msgReplyKTnxBye = [5]
-                            //msgHello        = [6]
-                            0 => {
-                                debug!("TxSubmission2Protocol received MsgRequestTxIds");
-                                let is_blocking = cbor_array[1] == Value::Bool(true);
-                                self.state = if is_blocking {
-                                    State::TxIdsBlocking
-                                } else {
-                                    State::TxIdsNonBlocking
-                                }
-                            },
-                            6 => {
-                                debug!("TxSubmission2Protocol received MsgHello");
-                                self.state = State::Idle
-                            }
-                            _ => {
-                                error!("unexpected message_id: {}", message_id);
-                            }
-                        }
-                    }
-                    _ => {
-                        error!("Unexpected cbor!")
-                    }
-                }
+        if !self.hello {
+            return self.inner.receive_data(data);
+        }
+        //msgHello = [6]
+        match de::from_slice::<Value>(&data[..]) {
+            Ok(Value::Array(cbor_array)) if cbor_array == [Value::Integer(6)] => {
+                debug!("TxSubmission2Protocol received MsgHello");
+                self.hello = false;
             }
             _ => {
-                error!("Unexpected cbor!")
+                let error = format!("TxSubmission2Protocol expected MsgHello, hex: {}", hex::encode(&data));
+                error!("{}", error);
+                self.inner.state = State::Done;
+                self.inner.result = Some(Err(error));
+                self.hello = false;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block::Era,
+        protocols::{
+            handshake::{PROTOCOL_VERSION_ALLEGRA, PROTOCOL_VERSION_N2C_16},
+            transaction::{tx_id, Tx},
+        },
+    };
+    use std::sync::{Arc, Mutex};
+
+    struct Source(Vec<Tx>);
+
+    struct Collector(Arc<Mutex<Vec<Tx>>>);
+
+    impl Listener for Collector {
+        fn handle_tx(&mut self, tx: &Tx) {
+            self.0.lock().unwrap().push(tx.clone());
+        }
+    }
+
+    impl TxSource for Source {
+        fn next_txs(&mut self, count: usize) -> Vec<Tx> {
+            let count = count.min(self.0.len());
+            self.0.drain(..count).collect()
+        }
+    }
+
+    fn request_tx_ids(blocking: bool, ack: i128, req: i128) -> Vec<u8> {
+        ser::to_vec_packed(&Value::Array(vec![
+            Value::Integer(0), Value::Bool(blocking), Value::Integer(ack), Value::Integer(req),
+        ])).unwrap()
+    }
+
+    #[test]
+    fn tx_submission2_client_works() {
+        let tx = Tx { era: Era::Conway, id: vec![1; 32], size: 1, body: vec![0x80] };
+        let mut client = TxSubmission2Protocol::new(Box::new(Source(vec![tx])));
+        assert_eq!(client.agency(), Agency::Client);
+        assert_eq!(client.state(), "Hello");
+        assert_eq!(client.send_data(), Some(vec![0x81, 0x06]));
+        assert_eq!(client.agency(), Agency::Server);
+
+        client.receive_data(request_tx_ids(true, 0, 3));
+        assert_eq!(client.agency(), Agency::Client);
+        let reply: Value = serde_cbor::de::from_slice(&client.send_data().unwrap()).unwrap();
+        assert_eq!(reply, Value::Array(vec![
+            Value::Integer(1),
+            Value::Array(vec![Value::Array(vec![
+                Value::Array(vec![Value::Integer(6), Value::Bytes(vec![1; 32])]),
+                Value::Integer(1),
+            ])]),
+        ]));
+
+        client.receive_data(request_tx_ids(true, 1, 3));
+        assert_eq!(client.send_data(), Some(vec![0x81, 0x04]));
+        assert_eq!(client.agency(), Agency::None);
+        assert_eq!(client.result(), Ok("Submitted 0 transactions".to_string()));
+    }
+
+    #[test]
+    fn tx_submission2_for_version_works() {
+        let client = TxSubmission2Protocol::for_version(PROTOCOL_VERSION_ALLEGRA, Box::new(Source(vec![]))).unwrap();
+        assert_eq!(client.agency(), Agency::Server);
+        let client = TxSubmission2Protocol::for_version(MIN_TX_SUBMISSION2_VERSION, Box::new(Source(vec![]))).unwrap();
+        assert_eq!(client.agency(), Agency::Client);
+        assert!(TxSubmission2Protocol::for_version(PROTOCOL_VERSION_N2C_16, Box::new(Source(vec![]))).is_err());
+    }
+
+    #[test]
+    fn tx_submission2_server_works() {
+        let body = vec![0x84, 0xa0, 0xa0, 0xf5, 0xf6];
+        let tx = Tx { era: Era::Conway, id: tx_id(&body).unwrap(), size: body.len() as u32, body };
+        let collected = Arc::new(Mutex::new(Vec::new()));
+        let mut server = TxSubmission2Protocol::collect(Box::new(Collector(collected.clone())));
+        let mut client = TxSubmission2Protocol::new(Box::new(Source(vec![tx.clone()])));
+        assert_eq!(server.role(), Agency::Server);
+        assert_eq!(server.protocol_id(), 0x8004);
+
+        /* Server waits for the client to say hello first. */
+        assert_eq!(server.agency(), Agency::Client);
+        assert_eq!(server.state(), "Hello");
+        server.receive_data(client.send_data().unwrap());
+        assert_eq!(server.agency(), Agency::Server);
+
+        while server.agency() != Agency::None {
+            assert_eq!(client.agency(), server.agency());
+            if server.agency() == Agency::Server {
+                client.receive_data(server.send_data().unwrap());
+            } else {
+                server.receive_data(client.send_data().unwrap());
+            }
+        }
+        assert_eq!(server.result(), Ok("Collected 1 transactions".to_string()));
+        assert_eq!(server.collected(), 1);
+        assert_eq!(*collected.lock().unwrap(), vec![tx]);
+    }
+
+    #[test]
+    fn tx_submission2_server_without_hello_works() {
+        let mut server = TxSubmission2Protocol::collect(Box::new(Collector(Arc::new(Mutex::new(Vec::new())))));
+        server.receive_data(vec![0x81, 0x04]);
+        assert_eq!(server.agency(), Agency::None);
+        assert!(server.result().is_err());
+    }
+}